@@ -0,0 +1,60 @@
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::sync::OnceLock;
+
+// The clock days are counted on, for daily rotation, rotated names and retention
+#[derive(Clone, Copy, Debug)]
+pub enum Clock {
+    Local,    // The server's local time
+    Utc,      // UTC, the same on every machine
+    Zone(Tz), // A named timezone, e.g. `America/New_York`
+}
+
+static CLOCK: OnceLock<Clock> = OnceLock::new();
+
+// Sets the clock, must be called at startup before any day is computed
+pub fn set(clock: Clock) {
+    if CLOCK.set(clock).is_err() {
+        info!("clock already set");
+    }
+}
+
+// Returns the clock set at startup, local time if none was
+pub fn get() -> Clock {
+    *CLOCK.get_or_init(|| Clock::Local)
+}
+
+pub fn parse_timezone(name: &str) -> Result<Tz, String> {
+    name.parse::<Tz>()
+        .map_err(|_| format!("unknown timezone \"{}\"", name))
+}
+
+impl Clock {
+    // Formats `time` as seen on this clock
+    pub fn format(&self, time: DateTime<Utc>, fmt: &str) -> String {
+        match self {
+            Clock::Local => time.with_timezone(&Local).format(fmt).to_string(),
+            Clock::Utc => time.format(fmt).to_string(),
+            Clock::Zone(tz) => time.with_timezone(tz).format(fmt).to_string(),
+        }
+    }
+
+    // Returns the next midnight after `time` on this clock
+    pub fn next_midnight(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Clock::Local => next_midnight(&Local, time),
+            Clock::Utc => next_midnight(&Utc, time),
+            Clock::Zone(tz) => next_midnight(tz, time),
+        }
+    }
+}
+
+fn next_midnight<Z: TimeZone>(zone: &Z, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let today: NaiveDate = time.with_timezone(zone).date_naive();
+    today
+        .succ_opt()?
+        .and_hms_opt(0, 0, 0)?
+        .and_local_timezone(zone.clone())
+        .earliest()
+        .map(|dt| dt.with_timezone(&Utc))
+}
@@ -0,0 +1,747 @@
+#[cfg(feature = "bzip2")]
+use bzip2::read::BzDecoder;
+#[cfg(feature = "bzip2")]
+use bzip2::write::BzEncoder;
+use clap::ValueEnum;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+#[cfg(feature = "lz4")]
+use lz4_flex::frame::{FrameDecoder, FrameEncoder};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{copy, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::Instant;
+use tar::{Archive, Builder};
+#[cfg(feature = "xz")]
+use xz2::read::XzDecoder;
+#[cfg(feature = "xz")]
+use xz2::write::XzEncoder;
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub(crate) enum Format {
+    Gzip,  // Fast, the default
+    Bzip2, // Better ratio than gzip on text, slower
+    #[value(alias = "lzma")]
+    Xz, // Better ratio for long-term archives, much slower
+    Lz4,   // Nearly free on CPU, for hosts that rotate very often
+}
+
+// Extensions of every supported format, used to recognise rotated files, including
+// the formats not compiled in since earlier runs may have used them. zstd is never
+// written but is recognised, as other tools compressing the same files use it.
+pub const EXTENSIONS: &[&str] = &["gz", "bz2", "xz", "lz4", "zst"];
+
+// Extension of archives encrypted with age, after the codec extension if compressed
+pub const ENCRYPTED_EXTENSION: &str = "age";
+
+// The buffer files are read and written through while compressing, larger than
+// the default of io::copy to cut down on system calls for large files
+const IO_BUF: usize = 256 * 1024;
+
+// The bytes encoded between two holes punched into a source removed afterwards,
+// about the disk space compressing it needs on top of the source
+const CONSUME_CHUNK: u64 = 8 * 1024 * 1024;
+
+// The first line of every age file
+#[cfg(feature = "age")]
+const AGE_HEADER: &[u8] = b"age-encryption.org/v1\n";
+
+// Extension of the SHA-256 sidecar written next to an archive
+pub const CHECKSUM_EXTENSION: &str = "sha256";
+
+// How rotated files are compressed
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Codec {
+    pub format: Format,
+    pub level: u32, // 1 (fastest) to 9 (smallest), lz4 has no levels
+}
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Gzip => "gz",
+            Format::Bzip2 => "bz2",
+            Format::Xz => "xz",
+            Format::Lz4 => "lz4",
+        }
+    }
+
+    // Whether the codec was compiled in, every codec but gzip is a Cargo feature
+    pub fn is_available(&self) -> bool {
+        match self {
+            Format::Gzip => true,
+            Format::Bzip2 => cfg!(feature = "bzip2"),
+            Format::Xz => cfg!(feature = "xz"),
+            Format::Lz4 => cfg!(feature = "lz4"),
+        }
+    }
+}
+
+// Parses a format name, rejecting the codecs that were not compiled in
+pub fn parse_format(name: &str) -> Result<Format, String> {
+    let format = Format::from_str(name, true)?;
+    if format.is_available() {
+        return Ok(format);
+    }
+    let available: Vec<_> = Format::value_variants()
+        .iter()
+        .filter(|f| f.is_available())
+        .filter_map(|f| f.to_possible_value())
+        .map(|v| v.get_name().to_string())
+        .collect();
+    Err(format!(
+        "codec \"{}\" is not compiled in, available codecs: {}",
+        name,
+        available.join(", ")
+    ))
+}
+
+fn unavailable(format: Format) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("codec {:?} is not compiled in", format),
+    )
+}
+
+// Streams `inf` through the encoder of the codec into `out`, returns `out` once
+// the encoder is finished along with the number of bytes read from `inf`
+fn encode(
+    inf: &mut File,
+    out: File,
+    codec: Codec,
+    consume: &mut Consume,
+) -> io::Result<(File, u64)> {
+    let out = BufWriter::with_capacity(IO_BUF, out);
+    let (out, len) = match codec.format {
+        Format::Gzip => {
+            let mut encoder = GzEncoder::new(out, Compression::new(codec.level));
+            let len = feed(inf, &mut encoder, GzEncoder::get_mut, consume)?;
+            (encoder.finish()?, len)
+        }
+        #[cfg(feature = "bzip2")]
+        Format::Bzip2 => {
+            let mut encoder = BzEncoder::new(out, bzip2::Compression::new(codec.level));
+            let len = feed(inf, &mut encoder, BzEncoder::get_mut, consume)?;
+            (encoder.finish()?, len)
+        }
+        #[cfg(feature = "xz")]
+        Format::Xz => {
+            let mut encoder = XzEncoder::new(out, codec.level);
+            let len = feed(inf, &mut encoder, XzEncoder::get_mut, consume)?;
+            (encoder.finish()?, len)
+        }
+        #[cfg(feature = "lz4")]
+        Format::Lz4 => {
+            let mut encoder = FrameEncoder::new(out);
+            let len = feed(inf, &mut encoder, FrameEncoder::get_mut, consume)?;
+            (encoder.finish().map_err(io::Error::other)?, len)
+        }
+        #[allow(unreachable_patterns)]
+        format => return Err(unavailable(format)),
+    };
+    let out = out.into_inner().map_err(|err| err.into_error())?;
+    Ok((out, len))
+}
+
+// The holes punched into the source while it is encoded, and the disk space taken
+// on top of what the source took at the start
+struct Consume {
+    chunk: Option<u64>, // The bytes encoded between two holes, none leaves the source whole
+    punched: u64,       // The length of the prefix of the source punched out
+    base: u64,          // The disk space the source took at the start
+    peak: u64,          // The most disk space taken on top of `base`
+}
+
+impl Consume {
+    fn new(source: &File, chunk: Option<u64>) -> io::Result<Self> {
+        Ok(Self {
+            chunk,
+            punched: 0,
+            base: allocated(source)?,
+            peak: 0,
+        })
+    }
+
+    fn measure(&mut self, source: &File, out: &File) -> io::Result<()> {
+        let used = allocated(source)? + allocated(out)?;
+        self.peak = self.peak.max(used.saturating_sub(self.base));
+        Ok(())
+    }
+}
+
+// Copies `inf` into `encoder`. When consuming, every chunk the encoder is flushed
+// and the archive synced, so what was read so far can be decoded back from the disk,
+// and only then is it punched out of `inf`.
+fn feed<W: Write>(
+    inf: &mut File,
+    encoder: &mut W,
+    out: fn(&mut W) -> &mut BufWriter<File>,
+    consume: &mut Consume,
+) -> io::Result<u64> {
+    let mut buf = vec![0; IO_BUF];
+    let mut len = 0;
+    loop {
+        let n = match inf.read(&mut buf) {
+            Ok(0) => return Ok(len),
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        encoder.write_all(&buf[..n])?;
+        len += n as u64;
+        let Some(chunk) = consume.chunk else {
+            continue;
+        };
+        if len - consume.punched < chunk {
+            continue;
+        }
+        encoder.flush()?;
+        let out = out(encoder).get_ref();
+        out.sync_data()?;
+        consume.measure(inf, out)?;
+        match punch_hole(inf, consume.punched, len - consume.punched) {
+            Ok(_) => consume.punched = len,
+            Err(err) => {
+                info!("leaving the source whole while compressing: {}", err);
+                consume.chunk = None;
+            }
+        }
+    }
+}
+
+// The disk space taken by `file`, less than its length once holes are punched
+fn allocated(file: &File) -> io::Result<u64> {
+    let meta = file.metadata()?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Ok(meta.blocks() * 512)
+    }
+    #[cfg(not(unix))]
+    Ok(meta.len())
+}
+
+// Frees the disk blocks holding `len` bytes of `file` from `offset`, which then
+// read back as zeros, leaving the length of the file as it is
+#[cfg(target_os = "linux")]
+fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    let res = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            mode,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    match res {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn punch_hole(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "punching holes is only supported on Linux",
+    ))
+}
+
+// The offset of the first data of `file` past the holes it starts with, its length
+// when it is all holes. The file is left at its start.
+#[cfg(target_os = "linux")]
+fn data_start(mut file: &File) -> io::Result<u64> {
+    use std::io::{Seek, SeekFrom};
+    use std::os::unix::io::AsRawFd;
+    let res = unsafe { libc::lseek(file.as_raw_fd(), 0, libc::SEEK_DATA) };
+    let start = match res {
+        0.. => res as u64,
+        _ => match io::Error::last_os_error() {
+            err if err.raw_os_error() == Some(libc::ENXIO) => file.metadata()?.len(),
+            err => return Err(err),
+        },
+    };
+    file.seek(SeekFrom::Start(0))?;
+    Ok(start)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn data_start(_file: &File) -> io::Result<u64> {
+    Ok(0)
+}
+
+// Decodes the archive at `filename` in full and checks that it holds `len` bytes.
+// Decoding also checks the format's own checksums (gzip CRC32, xz CRC64).
+fn verify(filename: &str, codec: Codec, len: u64) -> io::Result<()> {
+    let inf = BufReader::with_capacity(IO_BUF, File::open(filename)?);
+    let decoded = match codec.format {
+        Format::Gzip => copy(&mut GzDecoder::new(inf), &mut io::sink())?,
+        #[cfg(feature = "bzip2")]
+        Format::Bzip2 => copy(&mut BzDecoder::new(inf), &mut io::sink())?,
+        #[cfg(feature = "xz")]
+        Format::Xz => copy(&mut XzDecoder::new(inf), &mut io::sink())?,
+        #[cfg(feature = "lz4")]
+        Format::Lz4 => copy(&mut FrameDecoder::new(inf), &mut io::sink())?,
+        #[allow(unreachable_patterns)]
+        format => return Err(unavailable(format)),
+    };
+    if decoded != len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "archive \"{}\" holds {} bytes, expected {}",
+                filename, decoded, len
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// The temporary name an archive is written under: hidden and with a `.tmp` suffix, so
+// that a leftover from a crash never looks like a rotated file
+pub fn temp_path(target: &str) -> String {
+    let path = Path::new(target);
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.tmp", name))
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Compresses `filename` into `filename.<ext>` and removes the original, unless
+// `keep_original` is set, see `compress_to`
+pub fn compress_file(filename: &String, codec: Codec, keep_original: bool) -> io::Result<()> {
+    let target = format!("{}.{}", filename, codec.format.extension());
+    compress_to(filename, &target, codec, !keep_original)?;
+    if keep_original {
+        return Ok(());
+    }
+    fs::remove_file(filename)
+}
+
+// Compresses `source` into the archive `target`, see `compress_with`. With `consume`
+// set, `source` is emptied once the archive is in place and should be removed.
+pub fn compress_to(source: &str, target: &str, codec: Codec, consume: bool) -> io::Result<()> {
+    compress_with(source, target, codec, consume.then_some(CONSUME_CHUNK)).map(|_| ())
+}
+
+// Compresses `source` into the archive `target`.
+// The archive is written to `.target.tmp`, synced, decoded again to check it and
+// renamed into place, so a crash or a full disk never leaves a truncated archive
+// under the final name. The file is streamed through the encoder, never held in
+// memory.
+// Without `chunk`, the source is left in place and the disk space needed on top of
+// it is the size of the archive. With `chunk`, every `chunk` bytes encoded are
+// punched out of the source once the archive holding them is synced, so the space
+// needed is about one chunk. Should that fail half-way, the source is written back
+// from the archive; after a crash `restore_consumed` does it on the next start.
+// Returns the most disk space taken on top of the source, which is logged.
+fn compress_with(source: &str, target: &str, codec: Codec, chunk: Option<u64>) -> io::Result<u64> {
+    let tmp = temp_path(target);
+    let started = Instant::now();
+    let mut inf = File::options()
+        .read(true)
+        .write(chunk.is_some())
+        .open(source)?;
+    if data_start(&inf)? > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "\"{}\" starts with a hole left by an interrupted compression",
+                source
+            ),
+        ));
+    }
+    let mut consume = Consume::new(&inf, chunk)?;
+    let res = File::create(&tmp)
+        .and_then(|out| encode(&mut inf, out, codec, &mut consume))
+        .and_then(|(out, len)| {
+            out.sync_all()?;
+            consume.measure(&inf, &out)?;
+            Ok(len)
+        })
+        .and_then(|len| verify(&tmp, codec, len).map(|_| len))
+        .and_then(|len| fs::metadata(&tmp).map(|meta| (len, meta.len())))
+        .and_then(|sizes| fs::rename(&tmp, target).map(|_| sizes));
+    let (len, archive_len) = match res {
+        Ok(sizes) => sizes,
+        Err(err) if consume.punched > 0 => {
+            return match restore(&inf, &tmp, codec.format) {
+                Ok(_) => {
+                    let _ = fs::remove_file(&tmp);
+                    Err(err)
+                }
+                Err(restore_err) => Err(io::Error::other(format!(
+                    "{}, and \"{}\" is left with holes, to be restored from \"{}\": {}",
+                    err, source, tmp, restore_err
+                ))),
+            };
+        }
+        Err(err) => {
+            let _ = fs::remove_file(&tmp);
+            return Err(err);
+        }
+    };
+    if consume.punched > 0 {
+        // The source holds nothing but holes, what is left of it is empty
+        if let Err(err) = inf.set_len(0) {
+            error!("failed to empty \"{}\": {:+?}", source, err);
+        }
+    }
+    info!(
+        "compressed \"{}\" into \"{}\": {} bytes into {} in {:?}, {} bytes of disk space at peak",
+        source,
+        target,
+        len,
+        archive_len,
+        started.elapsed(),
+        consume.peak
+    );
+    Ok(consume.peak)
+}
+
+// Writes back the start of `source` punched out by an interrupted compression,
+// decoding the temporary archive `tmp` as far as it goes. Returns the bytes
+// written back, none when `source` does not start with a hole.
+pub fn restore_consumed(source: &str, tmp: &str, format: Format) -> io::Result<u64> {
+    let file = File::options().read(true).write(true).open(source)?;
+    restore(&file, tmp, format)
+}
+
+fn restore(source: &File, tmp: &str, format: Format) -> io::Result<u64> {
+    let holes = data_start(source)?;
+    if holes == 0 {
+        return Ok(0);
+    }
+    let inf = BufReader::with_capacity(IO_BUF, File::open(tmp)?);
+    let mut decoder: Box<dyn Read> = match format {
+        Format::Gzip => Box::new(GzDecoder::new(inf)),
+        #[cfg(feature = "bzip2")]
+        Format::Bzip2 => Box::new(BzDecoder::new(inf)),
+        #[cfg(feature = "xz")]
+        Format::Xz => Box::new(XzDecoder::new(inf)),
+        #[cfg(feature = "lz4")]
+        Format::Lz4 => Box::new(FrameDecoder::new(inf)),
+        #[allow(unreachable_patterns)]
+        format => return Err(unavailable(format)),
+    };
+    let len = source.metadata()?.len();
+    let mut out = BufWriter::with_capacity(IO_BUF, source);
+    let mut buf = vec![0; IO_BUF];
+    let mut restored = 0;
+    // The archive was synced up to the last hole punched, what follows may be cut off
+    while restored < len {
+        let max = buf.len().min((len - restored) as usize);
+        let n = match decoder.read(&mut buf[..max]) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        out.write_all(&buf[..n])?;
+        restored += n as u64;
+    }
+    out.flush()?;
+    if restored < holes {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "\"{}\" holds {} bytes of the {} punched out",
+                tmp, restored, holes
+            ),
+        ));
+    }
+    source.sync_all()?;
+    Ok(restored)
+}
+
+// Bundles `files` into the tar.gz archive `target`, each under its base name.
+// Like `compress_to`, the archive is written under a temp name, synced, read back
+// to check that every file is there in full and renamed into place. Removing the
+// files is left to the caller.
+pub fn bundle_files(files: &[String], target: &str, level: u32) -> io::Result<()> {
+    let tmp = temp_path(target);
+    let res = File::create(&tmp)
+        .and_then(|out| {
+            let mut builder = Builder::new(GzEncoder::new(out, Compression::new(level)));
+            for file in files {
+                let name = Path::new(file)
+                    .file_name()
+                    .ok_or_else(|| io::Error::other(format!("invalid file name: {}", file)))?;
+                builder.append_path_with_name(file, name)?;
+            }
+            builder.into_inner()?.finish()
+        })
+        .and_then(|out| out.sync_all())
+        .and_then(|_| verify_bundle(&tmp, files))
+        .and_then(|_| fs::rename(&tmp, target));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res
+}
+
+// Reads the bundle at `filename` back and checks that it holds `files` in order,
+// each with its full length, and that the gzip stream itself is intact
+fn verify_bundle(filename: &str, files: &[String]) -> io::Result<()> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut archive = Archive::new(GzDecoder::new(File::open(filename)?));
+    let mut entries = archive.entries()?;
+    for file in files {
+        let mut entry = entries
+            .next()
+            .ok_or_else(|| invalid(format!("bundle \"{}\" misses \"{}\"", filename, file)))??;
+        let len = copy(&mut entry, &mut io::sink())?;
+        if len != fs::metadata(file)?.len() {
+            return Err(invalid(format!(
+                "bundle \"{}\" holds {} bytes of \"{}\"",
+                filename, len, file
+            )));
+        }
+    }
+    if entries.next().is_some() {
+        return Err(invalid(format!(
+            "bundle \"{}\" holds unexpected files",
+            filename
+        )));
+    }
+    // Read up to the gzip trailer so its checksum is checked too
+    copy(&mut archive.into_inner(), &mut io::sink())?;
+    Ok(())
+}
+
+// Checks an age recipient (an X25519 public key, `age1...`), rejected when encryption
+// was not compiled in
+pub fn parse_recipient(key: &str) -> Result<String, String> {
+    #[cfg(feature = "age")]
+    {
+        key.parse::<age::x25519::Recipient>()
+            .map(|_| key.to_string())
+            .map_err(|err| err.to_string())
+    }
+    #[cfg(not(feature = "age"))]
+    {
+        let _ = key;
+        Err("encryption is not compiled in, build with the \"age\" feature".to_string())
+    }
+}
+
+// Encrypts `filename` to `recipient` into `filename.age` and removes the original.
+// Like `compress_to`, the result is written under a temp name, synced, checked to
+// start with the age header and renamed into place before the original is removed.
+// Returns the name of the encrypted file.
+#[cfg(feature = "age")]
+pub fn encrypt_file(filename: &str, recipient: &str) -> io::Result<String> {
+    let recipient: age::x25519::Recipient = recipient.parse().map_err(io::Error::other)?;
+    let target = format!("{}.{}", filename, ENCRYPTED_EXTENSION);
+    let tmp = temp_path(&target);
+    let mut inf = File::open(filename)?;
+    let res = File::create(&tmp)
+        .and_then(|out| {
+            let encryptor = age::Encryptor::with_recipients(vec![Box::new(recipient)])
+                .ok_or_else(|| io::Error::other("no recipient"))?;
+            let mut writer = encryptor.wrap_output(out).map_err(io::Error::other)?;
+            copy(&mut inf, &mut writer)?;
+            writer.finish()
+        })
+        .and_then(|out| out.sync_all())
+        .and_then(|_| verify_encrypted(&tmp))
+        .and_then(|_| fs::rename(&tmp, &target));
+    drop(inf);
+    if let Err(err) = res {
+        let _ = fs::remove_file(&tmp);
+        return Err(err);
+    }
+    fs::remove_file(filename)?;
+    Ok(target)
+}
+
+#[cfg(not(feature = "age"))]
+pub fn encrypt_file(filename: &str, _recipient: &str) -> io::Result<String> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "cannot encrypt \"{}\", encryption is not compiled in",
+            filename
+        ),
+    ))
+}
+
+// Checks that an encrypted file starts with the age header, which catches obviously
+// corrupt output without the identity needed to decrypt it
+#[cfg(feature = "age")]
+fn verify_encrypted(filename: &str) -> io::Result<()> {
+    use std::io::Read;
+    let mut header = vec![0u8; AGE_HEADER.len()];
+    File::open(filename)?.read_exact(&mut header)?;
+    if header != AGE_HEADER {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("\"{}\" has no age header", filename),
+        ));
+    }
+    Ok(())
+}
+
+// Writes the SHA-256 of `filename` to `filename.sha256`, in the format of `sha256sum`
+// so that `sha256sum -c` can check it from the same directory. The file is streamed
+// through the hasher and the sidecar is renamed into place once written.
+pub fn write_checksum(filename: &str) -> io::Result<()> {
+    let mut hasher = Sha256::new();
+    copy(&mut File::open(filename)?, &mut hasher)?;
+    let name = Path::new(filename)
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let target = format!("{}.{}", filename, CHECKSUM_EXTENSION);
+    let tmp = temp_path(&target);
+    let res = fs::write(&tmp, format!("{:x}  {}\n", hasher.finalize(), name))
+        .and_then(|_| fs::rename(&tmp, &target));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // Decodes the archive at `filename` in full
+    pub(crate) fn decode(filename: &Path, format: Format) -> Vec<u8> {
+        let inf = File::open(filename).unwrap();
+        let mut reader: Box<dyn Read> = match format {
+            Format::Gzip => Box::new(GzDecoder::new(inf)),
+            #[cfg(feature = "bzip2")]
+            Format::Bzip2 => Box::new(BzDecoder::new(inf)),
+            #[cfg(feature = "xz")]
+            Format::Xz => Box::new(XzDecoder::new(inf)),
+            #[cfg(feature = "lz4")]
+            Format::Lz4 => Box::new(FrameDecoder::new(inf)),
+            #[allow(unreachable_patterns)]
+            format => panic!("codec {:?} is not compiled in", format),
+        };
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        data
+    }
+
+    // The codecs compiled in
+    pub(crate) fn formats() -> Vec<Format> {
+        Format::value_variants()
+            .iter()
+            .filter(|format| format.is_available())
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn every_codec_decodes_back_to_the_original() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..200_000u32)
+            .flat_map(|n| n.to_string().into_bytes())
+            .collect();
+        for format in formats() {
+            let filename = dir.path().join(format!("out.{:?}", format));
+            fs::write(&filename, &data).unwrap();
+            let name = filename.to_string_lossy().into_owned();
+            compress_file(&name, Codec { format, level: 6 }, false).unwrap();
+            let archive = dir
+                .path()
+                .join(format!("out.{:?}.{}", format, format.extension()));
+            assert!(!filename.exists(), "{:?} kept the original", format);
+            assert_eq!(decode(&archive, format), data, "{:?} round trip", format);
+        }
+    }
+
+    // Bytes no codec can shrink, so an archive takes as much space as its source
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    // Leaves `source` and `tmp` as a crash compressing `data` into `tmp` would: the
+    // first `at` bytes synced into the archive and punched out of the source
+    pub(crate) fn interrupt(source: &Path, tmp: &Path, data: &[u8], at: usize) {
+        fs::write(source, data).unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::new(6));
+        encoder.write_all(&data[..at]).unwrap();
+        encoder.flush().unwrap();
+        fs::write(tmp, encoder.get_ref()).unwrap();
+        let file = File::options().write(true).open(source).unwrap();
+        punch_hole(&file, 0, at as u64).unwrap();
+    }
+
+    #[test]
+    fn consuming_the_source_bounds_the_extra_space() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = noise(2 * 1024 * 1024);
+        let chunk = 128 * 1024;
+        for format in formats() {
+            let source = dir.path().join(format!("out.{:?}", format));
+            let name = source.to_string_lossy().into_owned();
+            let target = format!("{}.{}", name, format.extension());
+            let codec = Codec { format, level: 1 };
+            fs::write(&source, &data).unwrap();
+
+            let whole = compress_with(&name, &target, codec, None).unwrap();
+            assert!(whole > 8 * chunk, "{:?}: {} bytes", format, whole);
+            assert_eq!(fs::read(&source).unwrap(), data);
+
+            let consumed = compress_with(&name, &target, codec, Some(chunk)).unwrap();
+            assert!(consumed < 4 * chunk, "{:?}: {} bytes", format, consumed);
+            assert_eq!(fs::metadata(&source).unwrap().len(), 0);
+            assert_eq!(decode(Path::new(&target), format), data, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn source_punched_out_by_an_interrupted_compression_is_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("out");
+        let name = source.to_string_lossy().into_owned();
+        let target = format!("{}.gz", name);
+        let tmp = temp_path(&target);
+        let data = noise(1024 * 1024);
+        interrupt(&source, Path::new(&tmp), &data, 600 * 1024);
+
+        let codec = Codec {
+            format: Format::Gzip,
+            level: 6,
+        };
+        let err = compress_to(&name, &target, codec, true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(!Path::new(&target).exists());
+
+        let restored = restore_consumed(&name, &tmp, Format::Gzip).unwrap();
+        assert!(restored >= 600 * 1024, "{} bytes restored", restored);
+        assert_eq!(fs::read(&source).unwrap(), data);
+        assert_eq!(restore_consumed(&name, &tmp, Format::Gzip).unwrap(), 0);
+    }
+
+    #[test]
+    fn codecs_not_compiled_in_are_rejected() {
+        for format in Format::value_variants() {
+            let name = format.to_possible_value().unwrap().get_name().to_string();
+            assert_eq!(
+                parse_format(&name).is_ok(),
+                format.is_available(),
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -2,22 +2,34 @@ extern crate clap;
 
 #[macro_use]
 mod utils;
+mod clock;
+mod compress;
+mod metrics;
+mod output;
 mod pm;
 mod rotate;
+mod worker;
 
-use clap::{Parser, ValueEnum};
-use std::fs::File;
-use std::io::{ErrorKind, Read};
+use chrono::format::StrftimeItems;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use regex::bytes::Regex;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::Path;
 use std::process::exit;
 use tokio::io::{stdin, AsyncReadExt};
+#[cfg(not(unix))]
 use tokio::signal::ctrl_c;
 use tokio::sync::broadcast;
 use tokio::sync::mpsc;
 use tokio::time::{sleep, Duration};
 use tokio::{join, select};
-use toml;
 use toml::Table;
 
+const DEFAULT_TIMESTAMP: &str = "%Y-%m-%d %H:%M:%S%.3f";
+
 #[derive(Parser, Debug, Clone)]
 #[command(version)]
 #[command(author = "XiaoYao<you.jianglong@gmail.com>")]
@@ -26,6 +38,7 @@ struct Args {
     #[arg(
         short,
         long,
+        global = true,
         env = "LOG_ROTATE_CONFIG",
         help = "Specifies the configuration file"
     )]
@@ -34,6 +47,7 @@ struct Args {
     #[arg(
         short,
         long,
+        global = true,
         env = "LOG_ROTATE_OUTPUT",
         help = "Specifies the output file"
     )]
@@ -48,15 +62,125 @@ struct Args {
     )]
     cut_mode: rotate::CutMode,
 
+    #[arg(
+        long,
+        env = "LOG_ROTATE_NO_ROTATE",
+        help = "Never rotates, the same as --cut-mode none"
+    )]
+    no_rotate: bool,
+
+    #[arg(
+        long,
+        global = true,
+        env = "LOG_ROTATE_UTC",
+        help = "Counts days in UTC rather than local time, for daily rotation, rotated names and retention"
+    )]
+    utc: bool,
+
+    #[arg(
+        long,
+        global = true,
+        env = "LOG_ROTATE_TIMEZONE",
+        conflicts_with = "utc",
+        value_parser = clock::parse_timezone,
+        help = "Counts days in the given timezone (e.g. America/New_York) rather than local time"
+    )]
+    timezone: Option<chrono_tz::Tz>,
+
     #[arg(
         long,
         short,
+        global = true,
         env = "LOG_KEEP_DAYS",
         default_value = "0",
-        help = "Specifies the log retention period"
+        value_parser = clap::value_parser!(i64).range(0..),
+        help = "Specifies the log retention period in days, 0 keeps rotated files forever"
     )]
     keep_days: i64,
 
+    #[arg(
+        long,
+        global = true,
+        env = "LOG_ROTATE_KEEP_NUM",
+        default_value = "0",
+        help = "Specifies the number of newest rotated files to keep, 0 keeps all"
+    )]
+    keep_num: usize,
+
+    #[arg(
+        long,
+        global = true,
+        env = "LOG_ROTATE_MAX_TOTAL_SIZE",
+        default_value = "0",
+        value_parser = parse_size,
+        help = "Removes the oldest rotated files once they take more than the given size in total (e.g. 10GB), 0 is unlimited"
+    )]
+    max_total_size: u64,
+
+    #[arg(
+        long,
+        global = true,
+        env = "LOG_ROTATE_RETENTION_INTERVAL",
+        default_value = "3600",
+        help = "Applies retention at startup and every given seconds, whether or not anything is written, 0 only applies it after rotations"
+    )]
+    retention_interval: u64,
+
+    #[arg(
+        long,
+        global = true,
+        env = "LOG_ROTATE_RETENTION_BY",
+        default_value = "name",
+        help = "Specifies where retention takes the age of rotated files from, their name or their modification time"
+    )]
+    retention_by: rotate::RetentionBy,
+
+    #[arg(
+        long,
+        global = true,
+        env = "LOG_ROTATE_RETENTION_LOOSE",
+        value_parser = parse_glob,
+        help = "Applies retention to any other file of the output's directory whose name matches the given glob (e.g. \"app-*.log\"), aged by its modification time"
+    )]
+    retention_loose: Option<glob::Pattern>,
+
+    #[arg(
+        long,
+        global = true,
+        env = "LOG_ROTATE_RETENTION_EXCLUDE",
+        value_delimiter = ',',
+        value_parser = parse_glob,
+        help = "Never removes the rotated files whose name matches the given glob (e.g. \"app.log.20260101*\"), can be repeated"
+    )]
+    retention_exclude: Vec<glob::Pattern>,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_MIN_FREE_SPACE",
+        default_value = "0",
+        value_parser = parse_size,
+        help = "Keeps the given free space (e.g. 500MB) on the filesystem by removing the oldest rotated files, then dropping incoming data, 0 disables the check"
+    )]
+    min_free_space: u64,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_INDEX_WIDTH",
+        default_value = "0",
+        help = "Zero-pads the sequence of rotated names to this width (e.g. 3 for -001), unpadded names sort -10 before -2"
+    )]
+    index_width: usize,
+
+    #[arg(
+        long,
+        global = true,
+        env = "LOG_ROTATE_SUFFIX_SEPARATOR",
+        default_value = ".",
+        value_parser = parse_separator,
+        help = "Specifies what joins the path and the date in rotated names"
+    )]
+    suffix_separator: String,
+
     #[arg(
         long,
         short = 's',
@@ -71,40 +195,603 @@ struct Args {
         short = 'z',
         env = "LOG_ROTATE_COMPRESS",
         default_value = "false",
-        help = "Specifies the compression level"
+        help = "Compresses rotated files"
     )]
     compress: bool,
 
     #[arg(
         long,
+        global = true,
+        env = "LOG_ROTATE_COMPRESS_FORMAT",
+        default_value = "gzip",
+        value_parser = compress::parse_format,
+        help = "Specifies the compression format: gzip, or bzip2, xz, lz4 if compiled in"
+    )]
+    compress_format: compress::Format,
+
+    #[arg(
+        long,
+        global = true,
+        env = "LOG_ROTATE_COMPRESS_LEVEL",
+        default_value = "6",
+        value_parser = clap::value_parser!(u32).range(1..=9),
+        help = "Specifies the compression level, from 1 (fastest) to 9 (smallest)"
+    )]
+    compress_level: u32,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_COMPRESS_JOBS",
+        default_value = "1",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Specifies how many files may be compressed at the same time"
+    )]
+    compress_jobs: u32,
+
+    #[arg(
+        long,
+        visible_alias = "compress-after",
+        env = "LOG_ROTATE_COMPRESS_DELAY",
+        default_value = "0",
+        help = "Leaves the given number of newest rotated files uncompressed, the older ones are compressed after each rotation and on the retention interval"
+    )]
+    compress_delay: usize,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_DELAY_COMPRESS",
+        help = "Leaves the newest rotated file uncompressed until the next rotation"
+    )]
+    delay_compress: bool,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_DAILY_BUNDLE",
+        help = "Bundles the files rotated on each past day into a single tar.gz"
+    )]
+    daily_bundle: bool,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_ENCRYPT_RECIPIENT",
+        value_parser = compress::parse_recipient,
+        help = "Encrypts rotated files, after compression, to the given age public key"
+    )]
+    encrypt_recipient: Option<String>,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_CHECKSUM",
+        help = "Writes the SHA-256 of each archive to a .sha256 file next to it"
+    )]
+    checksum: bool,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_COMPRESS_ON_EXIT",
+        help = "On a graceful shutdown, rotates the active file and compresses it like any rotated file (subject to --compress-delay), so the next run starts a new file; needs --compress or --encrypt-recipient. Without rotation the file is compressed in place"
+    )]
+    compress_on_exit: bool,
+
+    #[arg(
+        long,
+        global = true,
+        env = "LOG_ROTATE_SHRED",
+        help = "Overwrites expired files with zeros and syncs them before removing them"
+    )]
+    shred: bool,
+
+    #[arg(
+        long,
+        global = true,
+        env = "LOG_ROTATE_ARCHIVE_DIR",
+        help = "Moves rotated files into the given directory, relative to the active file's unless absolute, instead of next to the active file; created if missing"
+    )]
+    archive_dir: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        env = "LOG_ROTATE_EXPIRE_TO",
+        help = "Moves the files expired by retention into the given directory instead of removing them, prefixed with the time they were moved"
+    )]
+    expire_to: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        env = "LOG_ROTATE_TRASH_KEEP_DAYS",
+        default_value = "0",
+        value_parser = clap::value_parser!(i64).range(0..),
+        help = "Removes the files moved by --expire-to once they have been in the trash for the given days, 0 keeps them forever"
+    )]
+    trash_keep_days: i64,
+
+    #[arg(
+        long,
+        global = true,
+        env = "LOG_ROTATE_KEEP_UNCOMPRESSED",
+        help = "Keeps the uncompressed rotated file next to the compressed one"
+    )]
+    keep_uncompressed: bool,
+
+    #[arg(
+        long,
+        global = true,
+        env = "LOG_ROTATE_COMPRESS_MIN_SIZE",
+        default_value = "0",
+        value_parser = parse_size,
+        help = "Leaves rotated files smaller than the given size (e.g. 64KB) uncompressed"
+    )]
+    compress_min_size: u64,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_MAX_OPEN_RETRIES",
+        default_value = "3",
+        help = "Specifies how many times opening the log file is retried before data is dropped"
+    )]
+    max_open_retries: u32,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_REOPEN_CHECK",
+        default_value = "1",
+        help = "Checks every given seconds whether the log file was moved or deleted by another process and reopens it, 0 disables the check"
+    )]
+    reopen_check: u64,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_INCLUDE",
+        value_parser = Regex::new,
+        help = "Only writes lines matching the regex"
+    )]
+    include: Option<Regex>,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_EXCLUDE",
+        value_parser = Regex::new,
+        help = "Drops lines matching the regex"
+    )]
+    exclude: Option<Regex>,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_TIMESTAMP",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = DEFAULT_TIMESTAMP,
+        value_parser = parse_time_format,
+        help = "Prefixes each line with the current time, optionally in the given chrono format"
+    )]
+    timestamp: Option<String>,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_STRIP_CR",
+        help = "Writes the lines ending in \\r\\n with a plain \\n; filters and routes never see the \\r either way"
+    )]
+    strip_cr: bool,
+
+    #[arg(
+        long,
+        visible_alias = "buffer-size",
+        env = "LOG_ROTATE_MAX_BUFFER",
+        default_value = "64",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Specifies how many reads may be queued for writing, per output. Each slot holds one read buffer, so memory grows with it under bursts"
+    )]
+    max_buffer: u32,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_METRICS_ADDR",
+        help = "Serves Prometheus metrics over HTTP on the given address, like: `127.0.0.1:9000`"
+    )]
+    metrics_addr: Option<SocketAddr>,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_HEARTBEAT",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Logs a status line every given seconds in debug mode"
+    )]
+    heartbeat: Option<u64>,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_TEE",
+        default_value = "false",
+        help = "Also writes each line to stdout, or the descriptor given by --tee-fd"
+    )]
+    tee: bool,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_TEE_FD",
+        default_value = "1",
+        help = "Specifies the file descriptor used by --tee"
+    )]
+    tee_fd: i32,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_CHILD_STDIN",
+        default_value = "inherit",
+        help = "Specifies how the target process gets its stdin: ours directly, none (EOF right away), or ours copied through a pipe, which is closed when ours ends or the target process stops reading"
+    )]
+    child_stdin: pm::StdinMode,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_LINE_BUFFERED",
+        help = "Passes only whole lines of the target process's stdout and stderr on, so that their lines never interleave mid-line"
+    )]
+    line_buffered: bool,
+
+    #[arg(
+        long,
+        visible_alias = "tag-stream",
+        env = "LOG_ROTATE_TAG_SOURCE",
+        help = "Prefixes each line of the target process with the tag of the output it came from, [stdout] or [stderr] by default, implies --line-buffered"
+    )]
+    tag_source: bool,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_STDOUT_TAG",
+        default_value = "[stdout] ",
+        help = "The prefix of the lines of stdout with --tag-source, spaces included"
+    )]
+    stdout_tag: String,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_STDERR_TAG",
+        default_value = "[stderr] ",
+        help = "The prefix of the lines of stderr with --tag-source, spaces included"
+    )]
+    stderr_tag: String,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_STDERR_OUTPUT",
+        help = "Writes the stderr of the target process to its own file, rotated with the same options unless a [stderr_output] table in the config overrides them, instead of merging it into the output"
+    )]
+    stderr_output: Option<String>,
+
+    #[arg(skip)]
+    stderr_options: Option<rotate::Options>,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_TERM_TIMEOUT",
+        default_value = "10",
+        help = "Kills the target process if it hasn't exited the given seconds after being sent the signal we were stopped with (SIGTERM by default)"
+    )]
+    term_timeout: u64,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_POSTROTATE_SIGNAL",
+        value_parser = pm::parse_signal,
+        help = "Sends the given signal (e.g. SIGHUP or USR1) to the target process after each rotation, for processes that reopen their own files on it"
+    )]
+    postrotate_signal: Option<i32>,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_RESTART",
+        default_value = "never",
+        help = "Restarts the target process when it exits, with a backoff from 1s doubling up to 60s"
+    )]
+    restart: pm::Restart,
+
+    #[arg(
+        long,
+        visible_alias = "restart-max-retries",
+        env = "LOG_ROTATE_RESTART_MAX",
+        default_value = "0",
+        help = "Gives up restarting the target process after the given number of restarts in a row, 0 is unlimited"
+    )]
+    restart_max: u32,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_RESTART_WINDOW",
+        default_value = "0",
+        value_parser = parse_duration,
+        help = "Resets the count of restarts in a row once the target process stayed up for the given time (e.g. 5m), 0 never resets it"
+    )]
+    restart_window: Duration,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_SHELL",
+        help = "Runs the target process through the shell (sh -c, cmd /C on Windows) so pipes and globs work; the arguments are joined with spaces and interpreted by the shell, never pass it untrusted input"
+    )]
+    shell: bool,
+
+    #[arg(
+        long,
+        visible_alias = "workdir",
+        env = "LOG_ROTATE_CHDIR",
+        help = "Runs the target process in the given directory; relative output paths are still resolved from our own working directory"
+    )]
+    chdir: Option<String>,
+
+    #[arg(
+        long = "env",
+        value_name = "KEY=VAL",
+        value_parser = parse_env,
+        help = "Sets an environment variable of the target process, can be repeated; ${VAR} in the value is replaced with our own variable, and the flag wins over the same variable in the [env] config table"
+    )]
+    child_env: Vec<(String, String)>,
+
+    #[arg(
+        long,
+        visible_alias = "env-clear",
+        env = "LOG_ROTATE_CLEAR_ENV",
+        help = "Starts the target process with no environment but the variables given with --env"
+    )]
+    clear_env: bool,
+
+    #[arg(
+        long,
+        value_name = "KEY",
+        help = "Removes a variable from the environment of the target process, can be repeated"
+    )]
+    env_remove: Vec<String>,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_PTY",
+        help = "Runs the target process on a pseudo-terminal, so that it line-buffers its output and keeps its colors; its stderr shares the terminal, and so the stdout tag, unless --stderr-output is set (Unix only)"
+    )]
+    pty: bool,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_USER",
+        help = "Runs the target process as the given user name or uid, with its groups (Unix only, needs root); log files are still created as us"
+    )]
+    user: Option<String>,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_GROUP",
+        help = "Runs the target process with the given group name or gid, instead of the primary group of --user (Unix only, needs root)"
+    )]
+    group: Option<String>,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_FILE_HEADER",
+        help = "Writes the given line at the top of each new file, like a CSV header"
+    )]
+    file_header: Option<String>,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_TRIM_PARTIAL_LINE",
+        help = "Removes a partial last line left in the active file by a crash before appending to it"
+    )]
+    trim_partial_line: bool,
+
+    #[arg(
+        long,
+        global = true,
+        env = "LOG_ROTATE_DRY_RUN",
+        help = "Reports what rotation, compression and retention would move or remove without doing it, the active file is still written"
+    )]
+    dry_run: bool,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_SHUTDOWN_TIMEOUT",
+        default_value = "0",
+        help = "Gives up reading the target process's output and writing the queued data the given seconds after a shutdown signal, 0 waits until it is all written (the output is read for 5 seconds at most)"
+    )]
+    shutdown_timeout: u64,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_SYNC",
+        help = "Syncs the active file to disk before exiting, after the last data is written"
+    )]
+    sync: bool,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_MAX_AGE",
+        default_value = "0",
+        value_parser = parse_duration,
+        help = "In size mode, rotates the file on the first write once it is older than the given time (e.g. 24h) whatever its size, 0 disables it"
+    )]
+    max_age: Duration,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_MAX_LINE_LENGTH",
+        default_value = "0",
+        value_parser = parse_size,
+        help = "Splits a line once it grows past the given length (e.g. 1MB) without a newline, so a writer that never ends its line can't exhaust memory, 0 never splits"
+    )]
+    max_line_length: u64,
+
+    #[arg(
+        long,
+        env = "LOG_ROTATE_DELIMITER",
+        default_value = "\\n",
+        value_parser = parse_delimiter,
+        help = "The byte lines end with: a single character, or an escape such as \\0 or \\x1e for streams of NUL or record separated records"
+    )]
+    delimiter: u8,
+
+    #[arg(
+        long,
+        global = true,
         env = "LOG_ROTATE_DEBUG",
         default_value = "false",
-        help = "Specifies the debug switch"
+        help = "Shows what is going on besides the errors and warnings shown by default"
     )]
     debug: bool,
 
+    #[arg(
+        short,
+        long,
+        global = true,
+        env = "LOG_ROTATE_QUIET",
+        conflicts_with = "debug",
+        help = "Hides the errors and warnings, only the fatal errors we exit with are shown"
+    )]
+    quiet: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[clap(
         value_name = "ARGS",
         help = "Arguments to pass to the target process, first is the command, others are arguments, like: `ls -l -a`"
     )]
     args: Vec<String>,
+
+    #[arg(skip)]
+    outputs: Vec<output::Output>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    #[command(
+        about = "Compresses the existing rotated files of the outputs and exits, without reading any input"
+    )]
+    Compress,
+    #[command(
+        about = "Removes the rotated files of the outputs expired by retention and exits, without reading any input"
+    )]
+    Clean,
+    #[command(
+        about = "Rotates the given file once with the configured options and exits, without reading any input"
+    )]
+    RotateNow {
+        #[arg(
+            help = "The file to rotate, the options of the [[output]] with this path apply if any"
+        )]
+        path: String,
+    },
+    #[command(
+        about = "Lists the rotated files of the given file with their size and age, and what retention would remove, without removing anything"
+    )]
+    List {
+        #[arg(
+            help = "The file whose rotated files are listed, the options of the [[output]] with this path apply if any"
+        )]
+        path: String,
+    },
+    #[command(
+        about = "Rotates the files of a directory written by other processes whenever they are due, checking them on a timer, without reading any input"
+    )]
+    Watch {
+        #[arg(help = "The directory whose files are rotated")]
+        dir: String,
+        #[arg(
+            long,
+            default_value = "*.log",
+            value_parser = parse_glob,
+            help = "The names of the files rotated, the options of the [[output]] with the same path apply if any"
+        )]
+        pattern: glob::Pattern,
+        #[arg(
+            long,
+            default_value = "60s",
+            value_parser = parse_duration,
+            help = "How often the files are checked"
+        )]
+        interval: Duration,
+    },
+}
+
+impl Args {
+    // The rotation settings given by the top-level options
+    fn rotate_options(&self) -> rotate::Options {
+        rotate::Options {
+            path: self.output.clone(),
+            cut_mode: match self.no_rotate {
+                true => rotate::CutMode::None,
+                false => self.cut_mode.clone(),
+            },
+            file_size: self.file_size,
+            compress: self.compress.then_some(compress::Codec {
+                format: self.compress_format,
+                level: self.compress_level,
+            }),
+            compress_delay: match self.delay_compress {
+                true => self.compress_delay.max(1),
+                false => self.compress_delay,
+            },
+            compress_min_size: self.compress_min_size,
+            keep_uncompressed: self.keep_uncompressed,
+            daily_bundle: self.daily_bundle,
+            encrypt: self.encrypt_recipient.clone(),
+            checksum: self.checksum,
+            shred: self.shred,
+            compress_on_exit: self.compress_on_exit,
+            archive_dir: self.archive_dir.clone(),
+            expire_to: self.expire_to.clone(),
+            trash_keep_days: self.trash_keep_days,
+            keep_days: self.keep_days,
+            keep_num: self.keep_num,
+            max_total_size: self.max_total_size,
+            min_free_space: self.min_free_space,
+            retention_interval: (self.retention_interval > 0)
+                .then(|| Duration::from_secs(self.retention_interval)),
+            retention_by: self.retention_by.clone(),
+            retention_loose: self.retention_loose.clone(),
+            retention_exclude: self.retention_exclude.clone(),
+            index_width: self.index_width,
+            separator: self.suffix_separator.clone(),
+            filter: rotate::Filter {
+                include: self.include.clone(),
+                exclude: self.exclude.clone(),
+            },
+            timestamp: self.timestamp.clone(),
+            strip_cr: self.strip_cr,
+            heartbeat: self.heartbeat.map(Duration::from_secs),
+            tee: self.tee.then_some(self.tee_fd),
+            file_header: self.file_header.clone(),
+            reopen_check: (self.reopen_check > 0).then(|| Duration::from_secs(self.reopen_check)),
+            open_retries: self.max_open_retries,
+            trim_partial: self.trim_partial_line,
+            max_line_length: self.max_line_length as usize,
+            delimiter: self.delimiter,
+            shutdown_timeout: (self.shutdown_timeout > 0)
+                .then(|| Duration::from_secs(self.shutdown_timeout)),
+            sync: self.sync,
+            max_age: (!self.max_age.is_zero()).then_some(self.max_age),
+            on_rotate: None,
+        }
+    }
 }
 
-// This function sets up a signal handler for the interrupt signal (Ctrl+C)
-// The `running` parameter is an `Arc<AtomicBool>` which allows thread-safe access to the `running` variable
+// Waits for SIGTERM or SIGINT (Ctrl+C) and broadcasts the shutdown on `ch`, which
+// every task subscribes to. Returns without broadcasting if the shutdown comes from
+// elsewhere first, such as the rotation handler finishing once its input ends.
 async fn signal(ch: broadcast::Sender<()>) {
     let mut cr = ch.subscribe();
     select! {
-      s = ctrl_c() => {
-        // The `ctrl_c()` function returns a future that resolves when the interrupt signal is received
+      s = shutdown_signal() => {
         match s {
-            Ok(()) => {
-                log!("interrupted");
+            Ok(signal) => {
+                info!("interrupted by signal {}", signal);
+                // The child is sent the same signal on shutdown
+                pm::set_shutdown_signal(signal);
                 sleep(Duration::from_millis(500)).await;
                 ch.send(()).expect("broadcast send error");
             }
             Err(err) => {
-                log!("signal error: {}", err);
+                error!("signal error: {}", err);
             }
         }
       },
@@ -112,73 +799,921 @@ async fn signal(ch: broadcast::Sender<()>) {
     }
 }
 
+// Resolves with the number of the first SIGTERM or SIGINT received
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<i32> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut term = signal(SignalKind::terminate())?;
+    let mut int = signal(SignalKind::interrupt())?;
+    select! {
+        _ = term.recv() => Ok(libc::SIGTERM),
+        _ = int.recv() => Ok(libc::SIGINT),
+    }
+}
+
+// Resolves on Ctrl+C, the only signal handled outside Unix
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<i32> {
+    ctrl_c().await.map(|_| libc::SIGINT)
+}
+
+// A parsed config file. Its values sit between the environment and the defaults:
+// command-line flag > environment variable > config file > default
+struct Config<'a> {
+    table: &'a Table,
+    matches: &'a ArgMatches,
+}
+
+impl<'a> Config<'a> {
+    // Returns the value of `key`, unless the argument `id` it maps to was set
+    // explicitly on the command line or in the environment
+    fn get(&self, key: &str, id: &str) -> Option<&'a toml::Value> {
+        let val = self.table.get(key)?;
+        match self.matches.value_source(id) {
+            Some(source @ (ValueSource::CommandLine | ValueSource::EnvVariable)) => {
+                info!(
+                    "ignored \"{}\" from the config file, set by {:?}",
+                    key, source
+                );
+                None
+            }
+            _ => Some(val),
+        }
+    }
+}
+
+// Reports an invalid setting, from the config file or the command line, and exits
+fn config_error(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    exit(2)
+}
+
 fn parse_args() -> Args {
-    let mut args: Args = Args::parse(); // Parse command-line arguments
-    if let Some(ref config_file) = args.config {
+    let matches = Args::command().get_matches(); // Parse command-line arguments
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    utils::set_level(args.debug, args.quiet); // So overridden config values can be reported
+    if let Some(config_file) = args.config.clone() {
         // If a configuration file is specified, read and parse it
-        let mut file = File::open(config_file.as_str()).expect("Open config file failed");
-        let mut buf = String::new();
-        let size = file
-            .read_to_string(&mut buf)
-            .expect("Read config file failed");
-        if size == 0 {
-            log!("Config file is empty");
-            exit(1)
+        let buf = fs::read_to_string(&config_file).unwrap_or_else(|err| {
+            config_error(&format!(
+                "failed to read config file \"{}\": {}",
+                config_file, err
+            ))
+        });
+        if buf.is_empty() {
+            config_error(&format!("config file \"{}\" is empty", config_file));
         }
-        let table: Table = toml::from_str(buf.as_str()).expect("Parse config file failed");
-        if let Some(val) = table.get("output") {
-            args.output = Some(val.as_str().expect("\"output\" must be string").to_string());
+        let table: Table = toml::from_str(&buf).unwrap_or_else(|err| {
+            config_error(&format!(
+                "failed to parse config file \"{}\": {}",
+                config_file, err
+            ))
+        });
+        if let Err(msg) = apply_config(&mut args, &table, &matches) {
+            config_error(&msg);
         }
-        if let Some(val) = table.get("cut_mode") {
-            args.cut_mode =
-                rotate::CutMode::from_str(val.as_str().expect("\"cut_mode\" must be string"), true)
-                    .expect("cut_mode must be valid");
+    }
+    args
+}
+
+// Sets the values of the config file the command line leaves unset
+fn apply_config(args: &mut Args, table: &Table, matches: &ArgMatches) -> Result<(), String> {
+    let config = Config { table, matches };
+    let mut outputs = None;
+    if let Some(val) = table.get("output") {
+        if let Some(list) = val.as_array() {
+            outputs = Some(list);
+        } else if config.get("output", "output").is_some() {
+            args.output = Some(
+                val.as_str()
+                    .ok_or("\"output\" must be string or array of tables")?
+                    .to_string(),
+            );
         }
-        if let Some(val) = table.get("keep_num") {
-            args.keep_days = val.as_integer().expect("\"keep_num\" must be integer");
+    }
+    if let Some(val) = config.get("cut_mode", "cut_mode") {
+        args.cut_mode =
+            rotate::CutMode::from_str(val.as_str().ok_or("\"cut_mode\" must be string")?, true)
+                .map_err(|err| format!("\"cut_mode\" must be valid: {}", err))?;
+    }
+    if let Some(val) = config.get("no_rotate", "no_rotate") {
+        args.no_rotate = val.as_bool().ok_or("\"no_rotate\" must be bool")?;
+    }
+    if let Some(val) = config.get("utc", "utc") {
+        args.utc = val.as_bool().ok_or("\"utc\" must be bool")?;
+    }
+    if let Some(val) = config.get("timezone", "timezone") {
+        let val = val.as_str().ok_or("\"timezone\" must be string")?;
+        args.timezone = Some(
+            clock::parse_timezone(val)
+                .map_err(|err| format!("\"timezone\" must be valid: {}", err))?,
+        );
+    }
+    if let Some(val) = config.get("keep_days", "keep_days") {
+        args.keep_days = val
+            .as_integer()
+            .filter(|n| *n >= 0)
+            .ok_or("\"keep_days\" must be non-negative integer")?;
+    }
+    if let Some(val) = config.get("keep_num", "keep_num") {
+        args.keep_num = val
+            .as_integer()
+            .and_then(|n| usize::try_from(n).ok())
+            .ok_or("\"keep_num\" must be non-negative integer")?;
+    }
+    if let Some(val) = config.get("max_total_size", "max_total_size") {
+        args.max_total_size = config_size(val, "max_total_size")?;
+    }
+    if let Some(val) = config.get("trim_partial_line", "trim_partial_line") {
+        args.trim_partial_line = val.as_bool().ok_or("\"trim_partial_line\" must be bool")?;
+    }
+    if let Some(val) = config.get("min_free_space", "min_free_space") {
+        args.min_free_space = config_size(val, "min_free_space")?;
+    }
+    if let Some(val) = config.get("retention_interval", "retention_interval") {
+        args.retention_interval = val
+            .as_integer()
+            .and_then(|n| u64::try_from(n).ok())
+            .ok_or("\"retention_interval\" must be non-negative integer")?;
+    }
+    if let Some(val) = config.get("retention_by", "retention_by") {
+        args.retention_by = rotate::RetentionBy::from_str(
+            val.as_str().ok_or("\"retention_by\" must be string")?,
+            true,
+        )
+        .map_err(|err| format!("\"retention_by\" must be valid: {}", err))?;
+    }
+    if let Some(val) = config.get("retention_loose", "retention_loose") {
+        let val = val.as_str().ok_or("\"retention_loose\" must be string")?;
+        args.retention_loose = Some(
+            parse_glob(val).map_err(|err| format!("\"retention_loose\" must be valid: {}", err))?,
+        );
+    }
+    if let Some(val) = config.get("retention_exclude", "retention_exclude") {
+        args.retention_exclude = config_globs(val, "retention_exclude")?;
+    }
+    if let Some(val) = config.get("index_width", "index_width") {
+        args.index_width = val
+            .as_integer()
+            .and_then(|n| usize::try_from(n).ok())
+            .ok_or("\"index_width\" must be non-negative integer")?;
+    }
+    if let Some(val) = config.get("suffix_separator", "suffix_separator") {
+        let val = val.as_str().ok_or("\"suffix_separator\" must be string")?;
+        args.suffix_separator = parse_separator(val)
+            .map_err(|err| format!("\"suffix_separator\" must be valid: {}", err))?;
+    }
+    if let Some(val) = config.get("file_size", "file_size") {
+        args.file_size = Some(val.as_integer().ok_or("\"file_size\" must be integer")? as u64);
+    }
+    parse_compress(args, &config, false)?;
+    match table.get("compress") {
+        Some(toml::Value::Table(section)) => {
+            let section = Config {
+                table: section,
+                matches,
+            };
+            for key in section.table.keys() {
+                if !COMPRESS_KEYS.contains(&key.as_str()) {
+                    warn!("unknown key \"compress.{}\" in the config file", key);
+                }
+            }
+            if let Some(val) = section.get("enabled", "compress") {
+                args.compress = val.as_bool().ok_or("\"compress.enabled\" must be bool")?;
+            }
+            parse_compress(args, &section, true)?;
         }
-        if let Some(val) = table.get("file_size") {
-            args.file_size = Some(val.as_integer().expect("\"file_size\" must be integer") as u64);
+        _ => {
+            if let Some(val) = config.get("compress", "compress") {
+                args.compress = val.as_bool().ok_or("\"compress\" must be bool or table")?;
+            }
         }
-        if let Some(val) = table.get("compress") {
-            args.compress = val.as_bool().expect("\"compress\" must be bool");
+    }
+    if let Some(val) = config.get("include", "include") {
+        args.include = Some(parse_regex(val, "include")?);
+    }
+    if let Some(val) = config.get("exclude", "exclude") {
+        args.exclude = Some(parse_regex(val, "exclude")?);
+    }
+    if let Some(val) = config.get("timestamp", "timestamp") {
+        args.timestamp = match val {
+            toml::Value::Boolean(enable) => enable.then(|| DEFAULT_TIMESTAMP.to_string()),
+            _ => Some(
+                parse_time_format(val.as_str().ok_or("\"timestamp\" must be string or bool")?)
+                    .map_err(|err| format!("\"timestamp\" must be a valid time format: {}", err))?,
+            ),
+        };
+    }
+    if let Some(val) = config.get("strip_cr", "strip_cr") {
+        args.strip_cr = val.as_bool().ok_or("\"strip_cr\" must be bool")?;
+    }
+    let max_buffer = config
+        .get("max_buffer", "max_buffer")
+        .or_else(|| config.get("buffer_size", "max_buffer"));
+    if let Some(val) = max_buffer {
+        args.max_buffer = val
+            .as_integer()
+            .and_then(|n| u32::try_from(n).ok())
+            .filter(|&n| n > 0)
+            .ok_or("\"max_buffer\" must be positive integer")?;
+    }
+    if let Some(val) = config.get("metrics_addr", "metrics_addr") {
+        args.metrics_addr = Some(
+            val.as_str()
+                .ok_or("\"metrics_addr\" must be string")?
+                .parse()
+                .map_err(|err| format!("\"metrics_addr\" must be a socket address: {}", err))?,
+        );
+    }
+    if let Some(val) = config.get("heartbeat", "heartbeat") {
+        args.heartbeat = Some(
+            val.as_integer()
+                .and_then(|n| u64::try_from(n).ok())
+                .filter(|&n| n > 0)
+                .ok_or("\"heartbeat\" must be positive integer")?,
+        );
+    }
+    if let Some(val) = config.get("tee", "tee") {
+        args.tee = val.as_bool().ok_or("\"tee\" must be bool")?;
+    }
+    if let Some(val) = config.get("tee_fd", "tee_fd") {
+        args.tee_fd = val.as_integer().ok_or("\"tee_fd\" must be integer")? as i32;
+    }
+    if let Some(val) = config.get("child_stdin", "child_stdin") {
+        args.child_stdin =
+            pm::StdinMode::from_str(val.as_str().ok_or("\"child_stdin\" must be string")?, true)
+                .map_err(|err| format!("\"child_stdin\" must be valid: {}", err))?;
+    }
+    if let Some(val) = config.get("line_buffered", "line_buffered") {
+        args.line_buffered = val.as_bool().ok_or("\"line_buffered\" must be bool")?;
+    }
+    if let Some(val) = config.get("tag_source", "tag_source") {
+        args.tag_source = val.as_bool().ok_or("\"tag_source\" must be bool")?;
+    }
+    if let Some(val) = config.get("tag_stream", "tag_source") {
+        args.tag_source = val.as_bool().ok_or("\"tag_stream\" must be bool")?;
+    }
+    if let Some(val) = config.get("stdout_tag", "stdout_tag") {
+        args.stdout_tag = val
+            .as_str()
+            .ok_or("\"stdout_tag\" must be string")?
+            .to_string();
+    }
+    if let Some(val) = config.get("stderr_tag", "stderr_tag") {
+        args.stderr_tag = val
+            .as_str()
+            .ok_or("\"stderr_tag\" must be string")?
+            .to_string();
+    }
+    if let Some(val) = config.get("chdir", "chdir") {
+        args.chdir = Some(val.as_str().ok_or("\"chdir\" must be string")?.to_string());
+    }
+    if let Some(val) = config.get("workdir", "chdir") {
+        args.chdir = Some(
+            val.as_str()
+                .ok_or("\"workdir\" must be string")?
+                .to_string(),
+        );
+    }
+    // The variables of the table are merged with the flags rather than replaced,
+    // the flags go last so they win
+    if let Some(val) = config.table.get("env") {
+        let mut child_env: Vec<(String, String)> = val
+            .as_table()
+            .ok_or("\"env\" must be table of string")?
+            .iter()
+            .map(|(key, val)| {
+                let val = val.as_str().ok_or("\"env\" must be table of string")?;
+                Ok((key.clone(), expand_env(val)))
+            })
+            .collect::<Result<_, String>>()?;
+        child_env.append(&mut args.child_env);
+        args.child_env = child_env;
+    }
+    if let Some(val) = config.get("pty", "pty") {
+        args.pty = val.as_bool().ok_or("\"pty\" must be bool")?;
+    }
+    if let Some(val) = config.get("user", "user") {
+        args.user = Some(val.as_str().ok_or("\"user\" must be string")?.to_string());
+    }
+    if let Some(val) = config.get("group", "group") {
+        args.group = Some(val.as_str().ok_or("\"group\" must be string")?.to_string());
+    }
+    if let Some(val) = config.get("env_remove", "env_remove") {
+        args.env_remove = val
+            .as_array()
+            .ok_or("\"env_remove\" must be array of string")?
+            .iter()
+            .map(|key| {
+                key.as_str()
+                    .map(str::to_string)
+                    .ok_or("\"env_remove\" must be array of string")
+            })
+            .collect::<Result<_, _>>()?;
+    }
+    if let Some(val) = config.get("clear_env", "clear_env") {
+        args.clear_env = val.as_bool().ok_or("\"clear_env\" must be bool")?;
+    }
+    if let Some(val) = config.get("shell", "shell") {
+        args.shell = val.as_bool().ok_or("\"shell\" must be bool")?;
+    }
+    if let Some(val) = config.get("restart", "restart") {
+        args.restart =
+            pm::Restart::from_str(val.as_str().ok_or("\"restart\" must be string")?, true)
+                .map_err(|err| format!("\"restart\" must be valid: {}", err))?;
+    }
+    for key in ["restart_max", "restart_max_retries"] {
+        if let Some(val) = config.get(key, "restart_max") {
+            args.restart_max = val
+                .as_integer()
+                .and_then(|n| u32::try_from(n).ok())
+                .ok_or_else(|| format!("\"{}\" must be non-negative integer", key))?;
         }
-        if let Some(val) = table.get("debug") {
-            args.debug = val.as_bool().expect("\"debug\" must be bool");
+    }
+    if let Some(val) = config.get("restart_window", "restart_window") {
+        args.restart_window = config_duration(val, "restart_window")?;
+    }
+    if let Some(val) = config.get("term_timeout", "term_timeout") {
+        args.term_timeout = val
+            .as_integer()
+            .and_then(|n| u64::try_from(n).ok())
+            .ok_or("\"term_timeout\" must be non-negative integer")?;
+    }
+    if let Some(val) = config.get("postrotate_signal", "postrotate_signal") {
+        let val = val.as_str().ok_or("\"postrotate_signal\" must be string")?;
+        args.postrotate_signal = Some(
+            pm::parse_signal(val)
+                .map_err(|err| format!("\"postrotate_signal\" must be valid: {}", err))?,
+        );
+    }
+    // A table holds the path along with the options of stderr's own file
+    let mut stderr_table = None;
+    if let Some(val) = config.get("stderr_output", "stderr_output") {
+        match val.as_table() {
+            Some(table) => stderr_table = Some(table),
+            None => {
+                args.stderr_output = Some(
+                    val.as_str()
+                        .ok_or("\"stderr_output\" must be string or table")?
+                        .to_string(),
+                )
+            }
         }
-        if let Some(val) = table.get("exec") {
-            args.args = val
-                .as_array()
-                .expect("\"exec\" must be array of string")
-                .iter()
-                .map(|x| {
-                    x.as_str()
-                        .expect("\"exec\" must be array of string")
-                        .to_string()
-                })
-                .collect();
+    }
+    if let Some(val) = config.get("file_header", "file_header") {
+        args.file_header = Some(
+            val.as_str()
+                .ok_or("\"file_header\" must be string")?
+                .to_string(),
+        );
+    }
+    if let Some(val) = config.get("keep_uncompressed", "keep_uncompressed") {
+        args.keep_uncompressed = val.as_bool().ok_or("\"keep_uncompressed\" must be bool")?;
+    }
+    if let Some(val) = config.get("encrypt_recipient", "encrypt_recipient") {
+        args.encrypt_recipient = Some(
+            compress::parse_recipient(val.as_str().ok_or("\"encrypt_recipient\" must be string")?)
+                .map_err(|err| format!("\"encrypt_recipient\" must be valid: {}", err))?,
+        );
+    }
+    if let Some(val) = config.get("archive_dir", "archive_dir") {
+        args.archive_dir = Some(
+            val.as_str()
+                .ok_or("\"archive_dir\" must be string")?
+                .to_string(),
+        );
+    }
+    if let Some(val) = config.get("expire_to", "expire_to") {
+        args.expire_to = Some(
+            val.as_str()
+                .ok_or("\"expire_to\" must be string")?
+                .to_string(),
+        );
+    }
+    if let Some(val) = config.get("trash_keep_days", "trash_keep_days") {
+        args.trash_keep_days = val
+            .as_integer()
+            .filter(|n| *n >= 0)
+            .ok_or("\"trash_keep_days\" must be non-negative integer")?;
+    }
+    if let Some(val) = config.get("compress_on_exit", "compress_on_exit") {
+        args.compress_on_exit = val.as_bool().ok_or("\"compress_on_exit\" must be bool")?;
+    }
+    if let Some(val) = config.get("shred", "shred") {
+        args.shred = val.as_bool().ok_or("\"shred\" must be bool")?;
+    }
+    if let Some(val) = config.get("checksum", "checksum") {
+        args.checksum = val.as_bool().ok_or("\"checksum\" must be bool")?;
+    }
+    if let Some(val) = config.get("daily_bundle", "daily_bundle") {
+        args.daily_bundle = val.as_bool().ok_or("\"daily_bundle\" must be bool")?;
+    }
+    if let Some(val) = config.get("delay_compress", "delay_compress") {
+        args.delay_compress = val.as_bool().ok_or("\"delay_compress\" must be bool")?;
+    }
+    if let Some(val) = config.get("reopen_check", "reopen_check") {
+        args.reopen_check = val
+            .as_integer()
+            .and_then(|n| u64::try_from(n).ok())
+            .ok_or("\"reopen_check\" must be non-negative integer")?;
+    }
+    if let Some(val) = config.get("max_open_retries", "max_open_retries") {
+        args.max_open_retries = val
+            .as_integer()
+            .and_then(|n| u32::try_from(n).ok())
+            .ok_or("\"max_open_retries\" must be non-negative integer")?;
+    }
+    if let Some(val) = config.get("max_line_length", "max_line_length") {
+        args.max_line_length = config_size(val, "max_line_length")?;
+    }
+    if let Some(val) = config.get("delimiter", "delimiter") {
+        let val = val.as_str().ok_or("\"delimiter\" must be string")?;
+        args.delimiter =
+            parse_delimiter(val).map_err(|err| format!("\"delimiter\" must be valid: {}", err))?;
+    }
+    if let Some(val) = config.get("shutdown_timeout", "shutdown_timeout") {
+        args.shutdown_timeout = val
+            .as_integer()
+            .and_then(|n| u64::try_from(n).ok())
+            .ok_or("\"shutdown_timeout\" must be non-negative integer")?;
+    }
+    if let Some(val) = config.get("max_age", "max_age") {
+        args.max_age = config_duration(val, "max_age")?;
+    }
+    if let Some(val) = config.get("sync", "sync") {
+        args.sync = val.as_bool().ok_or("\"sync\" must be bool")?;
+    }
+    if let Some(val) = config.get("dry_run", "dry_run") {
+        args.dry_run = val.as_bool().ok_or("\"dry_run\" must be bool")?;
+    }
+    if let Some(val) = config.get("debug", "debug") {
+        args.debug = val.as_bool().ok_or("\"debug\" must be bool")?;
+    }
+    if let Some(val) = config.get("quiet", "quiet") {
+        args.quiet = val.as_bool().ok_or("\"quiet\" must be bool")?;
+    }
+    if let Some(val) = config.get("exec", "args") {
+        args.args = val
+            .as_array()
+            .ok_or("\"exec\" must be array of string")?
+            .iter()
+            .map(|x| {
+                x.as_str()
+                    .map(str::to_string)
+                    .ok_or("\"exec\" must be array of string")
+            })
+            .collect::<Result<_, _>>()?;
+        if args.args.is_empty() {
+            return Err("\"exec\" must not be empty, leave it out to read stdin".to_string());
         }
     }
-    args
+    if let Some(list) = outputs {
+        // Parsed last so that every `[[output]]` inherits the top-level settings
+        let defaults = args.rotate_options();
+        args.outputs = list
+            .iter()
+            .map(|x| {
+                parse_output(
+                    x.as_table().ok_or("\"output\" must be array of tables")?,
+                    &defaults,
+                    compress::Codec {
+                        format: args.compress_format,
+                        level: args.compress_level,
+                    },
+                )
+            })
+            .collect::<Result<_, _>>()?;
+    }
+    if let Some(table) = stderr_table {
+        let output = parse_output(
+            table,
+            &args.rotate_options(),
+            compress::Codec {
+                format: args.compress_format,
+                level: args.compress_level,
+            },
+        )?;
+        args.stderr_output = output.options.path.clone();
+        args.stderr_options = Some(output.options);
+    }
+    Ok(())
+}
+
+// The keys of the `[compress]` table
+const COMPRESS_KEYS: &[&str] = &["enabled", "format", "level", "jobs", "min_size", "delay"];
+
+// Reads the compression settings, either the top-level `compress_*` keys or the keys
+// of the `[compress]` table (`section`), which are the same without the prefix
+fn parse_compress(args: &mut Args, config: &Config, section: bool) -> Result<(), String> {
+    let key = |name: &str| match section {
+        true => name.to_string(),
+        false => format!("compress_{}", name),
+    };
+    let label = |name: &str| match section {
+        true => format!("compress.{}", name),
+        false => format!("compress_{}", name),
+    };
+    if let Some(val) = config.get(&key("format"), "compress_format") {
+        args.compress_format = compress::parse_format(
+            val.as_str()
+                .ok_or_else(|| format!("\"{}\" must be string", label("format")))?,
+        )
+        .map_err(|err| format!("\"{}\" must be valid: {}", label("format"), err))?;
+    }
+    if let Some(val) = config.get(&key("level"), "compress_level") {
+        args.compress_level = val
+            .as_integer()
+            .and_then(|n| u32::try_from(n).ok())
+            .filter(|n| (1..=9).contains(n))
+            .ok_or_else(|| format!("\"{}\" must be integer from 1 to 9", label("level")))?;
+    }
+    if let Some(val) = config.get(&key("jobs"), "compress_jobs") {
+        args.compress_jobs = val
+            .as_integer()
+            .and_then(|n| u32::try_from(n).ok())
+            .filter(|&n| n > 0)
+            .ok_or_else(|| format!("\"{}\" must be positive integer", label("jobs")))?;
+    }
+    if let Some(val) = config.get(&key("delay"), "compress_delay") {
+        args.compress_delay = val
+            .as_integer()
+            .and_then(|n| usize::try_from(n).ok())
+            .ok_or_else(|| format!("\"{}\" must be non-negative integer", label("delay")))?;
+    }
+    if let Some(val) = config.get(&key("min_size"), "compress_min_size") {
+        args.compress_min_size = config_size(val, &label("min_size"))?;
+    }
+    Ok(())
+}
+
+// Checks that a chrono format string has no invalid specifiers
+fn parse_time_format(fmt: &str) -> Result<String, String> {
+    StrftimeItems::new(fmt)
+        .parse()
+        .map(|_| fmt.to_string())
+        .map_err(|err| err.to_string())
+}
+
+// Checks the separator of rotated names, it must keep them in the same directory
+fn parse_separator(separator: &str) -> Result<String, String> {
+    if separator.is_empty()
+        || separator.contains(std::path::MAIN_SEPARATOR)
+        || separator.contains('/')
+    {
+        return Err(format!("invalid separator \"{}\"", separator));
+    }
+    Ok(separator.to_string())
+}
+
+// Parses the line delimiter: a single ASCII character, or one of the escapes \n,
+// \r, \t, \0 and \xHH
+fn parse_delimiter(delimiter: &str) -> Result<u8, String> {
+    let byte = match delimiter {
+        "\\n" => Some(b'\n'),
+        "\\r" => Some(b'\r'),
+        "\\t" => Some(b'\t'),
+        "\\0" => Some(0),
+        _ => match delimiter.strip_prefix("\\x") {
+            Some(hex) if hex.len() == 2 => u8::from_str_radix(hex, 16).ok(),
+            Some(_) => None,
+            None if delimiter.len() == 1 && delimiter.is_ascii() => Some(delimiter.as_bytes()[0]),
+            None => None,
+        },
+    };
+    byte.ok_or_else(|| format!("invalid delimiter \"{}\"", delimiter))
+}
+
+// Parses an environment variable given as KEY=VAL
+fn parse_env(var: &str) -> Result<(String, String), String> {
+    match var.split_once('=') {
+        Some((key, val)) if !key.is_empty() => Ok((key.to_string(), expand_env(val))),
+        _ => Err(format!("invalid variable \"{}\", expected KEY=VAL", var)),
+    }
+}
+
+// Replaces each ${VAR} in the value with our own variable, empty if it isn't set;
+// an unclosed ${ is kept as it is
+fn expand_env(val: &str) -> String {
+    let mut expanded = String::with_capacity(val.len());
+    let mut rest = val;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start + 2..].find('}') else {
+            break;
+        };
+        expanded.push_str(&rest[..start]);
+        let name = &rest[start + 2..start + 2 + len];
+        expanded.push_str(&std::env::var(name).unwrap_or_default());
+        rest = &rest[start + 3 + len..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+// Checks a glob matched against file names
+fn parse_glob(pattern: &str) -> Result<glob::Pattern, String> {
+    glob::Pattern::new(pattern).map_err(|err| err.to_string())
+}
+
+// Parses a size in bytes with an optional unit: K/KB/KiB, M/MB/MiB or G/GB/GiB,
+// all of them powers of 1024
+fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (num, unit) = size.split_at(split);
+    let num: u64 = num
+        .parse()
+        .map_err(|_| format!("invalid size \"{}\"", size))?;
+    let shift = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        _ => return Err(format!("invalid size unit \"{}\"", unit)),
+    };
+    num.checked_mul(1 << shift)
+        .ok_or_else(|| format!("size \"{}\" is too large", size))
+}
+
+// Parses a duration with an optional unit: s, m, h or d, seconds without one
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let duration = duration.trim();
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (num, unit) = duration.split_at(split);
+    let num: u64 = num
+        .parse()
+        .map_err(|_| format!("invalid duration \"{}\"", duration))?;
+    let secs = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(format!("invalid duration unit \"{}\"", unit)),
+    };
+    num.checked_mul(secs)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration \"{}\" is too large", duration))
+}
+
+// Reads a duration from the config file, either a number of seconds or a string
+// with a unit
+fn config_duration(val: &toml::Value, key: &str) -> Result<Duration, String> {
+    match val {
+        toml::Value::Integer(n) => u64::try_from(*n)
+            .map(Duration::from_secs)
+            .map_err(|_| format!("\"{}\" must be non-negative", key)),
+        _ => parse_duration(
+            val.as_str()
+                .ok_or_else(|| format!("\"{}\" must be integer or string", key))?,
+        )
+        .map_err(|err| format!("\"{}\" must be a valid duration: {}", key, err)),
+    }
+}
+
+// Reads a size from the config file, either a number of bytes or a string with a unit
+fn config_size(val: &toml::Value, key: &str) -> Result<u64, String> {
+    match val {
+        toml::Value::Integer(n) => {
+            u64::try_from(*n).map_err(|_| format!("\"{}\" must be non-negative", key))
+        }
+        _ => parse_size(
+            val.as_str()
+                .ok_or_else(|| format!("\"{}\" must be integer or string", key))?,
+        )
+        .map_err(|err| format!("\"{}\" must be a valid size: {}", key, err)),
+    }
+}
+
+// Parses a glob or an array of globs
+fn config_globs(val: &toml::Value, key: &str) -> Result<Vec<glob::Pattern>, String> {
+    let parse = |val: &toml::Value| {
+        let val = val
+            .as_str()
+            .ok_or_else(|| format!("\"{}\" must be string or array of string", key))?;
+        parse_glob(val).map_err(|err| format!("\"{}\" must be valid: {}", key, err))
+    };
+    match val.as_array() {
+        Some(list) => list.iter().map(parse).collect(),
+        None => Ok(vec![parse(val)?]),
+    }
+}
+
+fn parse_regex(val: &toml::Value, key: &str) -> Result<Regex, String> {
+    let pattern = val
+        .as_str()
+        .ok_or_else(|| format!("\"{}\" must be string", key))?;
+    Regex::new(pattern).map_err(|err| format!("\"{}\" must be a valid regex: {}", key, err))
+}
+
+// Parses an `[[output]]` section, keys that are not set fall back to `defaults`
+fn parse_output(
+    table: &Table,
+    defaults: &rotate::Options,
+    default_codec: compress::Codec,
+) -> Result<output::Output, String> {
+    let mut options = defaults.clone();
+    let path = table
+        .get("path")
+        .ok_or("\"output.path\" is required")?
+        .as_str()
+        .ok_or("\"output.path\" must be string")?
+        .to_string();
+    options.path = Some(path.clone());
+    if let Some(val) = table.get("cut_mode") {
+        options.cut_mode = rotate::CutMode::from_str(
+            val.as_str().ok_or("\"output.cut_mode\" must be string")?,
+            true,
+        )
+        .map_err(|err| format!("\"output.cut_mode\" must be valid: {}", err))?;
+    }
+    if let Some(val) = table.get("file_size") {
+        options.file_size = Some(
+            val.as_integer()
+                .ok_or("\"output.file_size\" must be integer")? as u64,
+        );
+    }
+    let mut codec = options.compress.unwrap_or(default_codec);
+    if let Some(val) = table.get("index_width") {
+        options.index_width = val
+            .as_integer()
+            .and_then(|n| usize::try_from(n).ok())
+            .ok_or("\"output.index_width\" must be non-negative integer")?;
+    }
+    if let Some(val) = table.get("suffix_separator") {
+        let val = val
+            .as_str()
+            .ok_or("\"output.suffix_separator\" must be string")?;
+        options.separator = parse_separator(val)
+            .map_err(|err| format!("\"output.suffix_separator\" must be valid: {}", err))?;
+    }
+    if let Some(val) = table.get("compress_format") {
+        codec.format = compress::parse_format(
+            val.as_str()
+                .ok_or("\"output.compress_format\" must be string")?,
+        )
+        .map_err(|err| format!("\"output.compress_format\" must be valid: {}", err))?;
+    }
+    if let Some(val) = table.get("compress_level") {
+        codec.level = val
+            .as_integer()
+            .and_then(|n| u32::try_from(n).ok())
+            .filter(|n| (1..=9).contains(n))
+            .ok_or("\"output.compress_level\" must be integer from 1 to 9")?;
+    }
+    let enabled = match table.get("compress") {
+        Some(val) => val.as_bool().ok_or("\"output.compress\" must be bool")?,
+        None => options.compress.is_some(),
+    };
+    options.compress = enabled.then_some(codec);
+    if let Some(val) = table.get("compress_delay") {
+        options.compress_delay = val
+            .as_integer()
+            .and_then(|n| usize::try_from(n).ok())
+            .ok_or("\"output.compress_delay\" must be non-negative integer")?;
+    }
+    if let Some(val) = table.get("compress_min_size") {
+        options.compress_min_size = config_size(val, "output.compress_min_size")?;
+    }
+    if let Some(val) = table.get("max_age") {
+        let max_age = config_duration(val, "output.max_age")?;
+        options.max_age = (!max_age.is_zero()).then_some(max_age);
+    }
+    if let Some(val) = table.get("daily_bundle") {
+        options.daily_bundle = val
+            .as_bool()
+            .ok_or("\"output.daily_bundle\" must be bool")?;
+    }
+    if let Some(val) = table.get("delay_compress") {
+        if val
+            .as_bool()
+            .ok_or("\"output.delay_compress\" must be bool")?
+        {
+            options.compress_delay = options.compress_delay.max(1);
+        }
+    }
+    if let Some(val) = table.get("archive_dir") {
+        options.archive_dir = Some(
+            val.as_str()
+                .ok_or("\"output.archive_dir\" must be string")?
+                .to_string(),
+        );
+    }
+    if let Some(val) = table.get("expire_to") {
+        options.expire_to = Some(
+            val.as_str()
+                .ok_or("\"output.expire_to\" must be string")?
+                .to_string(),
+        );
+    }
+    if let Some(val) = table.get("trash_keep_days") {
+        options.trash_keep_days = val
+            .as_integer()
+            .filter(|n| *n >= 0)
+            .ok_or("\"output.trash_keep_days\" must be non-negative integer")?;
+    }
+    if let Some(val) = table.get("compress_on_exit") {
+        options.compress_on_exit = val
+            .as_bool()
+            .ok_or("\"output.compress_on_exit\" must be bool")?;
+    }
+    if let Some(val) = table.get("shred") {
+        options.shred = val.as_bool().ok_or("\"output.shred\" must be bool")?;
+    }
+    if let Some(val) = table.get("keep_days") {
+        options.keep_days = val
+            .as_integer()
+            .filter(|n| *n >= 0)
+            .ok_or("\"output.keep_days\" must be non-negative integer")?;
+    }
+    if let Some(val) = table.get("keep_num") {
+        options.keep_num = val
+            .as_integer()
+            .and_then(|n| usize::try_from(n).ok())
+            .ok_or("\"output.keep_num\" must be non-negative integer")?;
+    }
+    if let Some(val) = table.get("max_total_size") {
+        options.max_total_size = config_size(val, "output.max_total_size")?;
+    }
+    if let Some(val) = table.get("min_free_space") {
+        options.min_free_space = config_size(val, "output.min_free_space")?;
+    }
+    if let Some(val) = table.get("retention_interval") {
+        let secs = val
+            .as_integer()
+            .and_then(|n| u64::try_from(n).ok())
+            .ok_or("\"output.retention_interval\" must be non-negative integer")?;
+        options.retention_interval = (secs > 0).then(|| Duration::from_secs(secs));
+    }
+    if let Some(val) = table.get("retention_exclude") {
+        options.retention_exclude = config_globs(val, "output.retention_exclude")?;
+    }
+    if let Some(val) = table.get("retention_by") {
+        options.retention_by = rotate::RetentionBy::from_str(
+            val.as_str()
+                .ok_or("\"output.retention_by\" must be string")?,
+            true,
+        )
+        .map_err(|err| format!("\"output.retention_by\" must be valid: {}", err))?;
+    }
+    if let Some(val) = table.get("retention_loose") {
+        let val = val
+            .as_str()
+            .ok_or("\"output.retention_loose\" must be string")?;
+        options.retention_loose = Some(
+            parse_glob(val)
+                .map_err(|err| format!("\"output.retention_loose\" must be valid: {}", err))?,
+        );
+    }
+    if let Some(val) = table.get("strip_cr") {
+        options.strip_cr = val.as_bool().ok_or("\"output.strip_cr\" must be bool")?;
+    }
+    if let Some(val) = table.get("include") {
+        options.filter.include = Some(parse_regex(val, "output.include")?);
+    }
+    if let Some(val) = table.get("exclude") {
+        options.filter.exclude = Some(parse_regex(val, "output.exclude")?);
+    }
+    let pattern = table
+        .get("match")
+        .map(|val| parse_regex(val, "output.match"))
+        .transpose()?;
+    let name = match table.get("name") {
+        Some(val) => val
+            .as_str()
+            .ok_or("\"output.name\" must be string")?
+            .to_string(),
+        None => path,
+    };
+    Ok(output::Output {
+        name,
+        pattern,
+        options,
+    })
 }
 
-async fn stdin_read(sender: mpsc::Sender<Vec<u8>>, ch: broadcast::Sender<()>) {
+// Copies our stdin into the channel until EOF or shutdown, returns the exit code:
+// 0 then, 1 if stdin could not be read
+async fn stdin_read(sender: mpsc::Sender<Vec<u8>>, ch: broadcast::Sender<()>) -> i32 {
     let mut stdin = stdin(); // Create a handle to the standard input
     let mut cr = ch.subscribe();
+    let mut code = 0;
     loop {
         let mut buf = Vec::new(); // Create a buffer to read input
         select! {
           res = stdin.read_buf(&mut buf) => {
-            if buf.len() > 0 {
-                if let Err(err) = sender.send(buf).await {
+            if !buf.is_empty() {
+                if let Err(err) = utils::send(&sender, buf, "stdin").await {
                     // Write the input to the output file
-                    log!("write failed: {:+?}", err); // Print an error message if the write operation fails
+                    error!("write failed: {:+?}", err); // Print an error message if the write operation fails
                 }
             }
             match res {
                 Ok(len) => { // If input was successfully read
                     if len < 1 { // If the length of the input is less than 1, it means that the input has been closed
-                        log!("stdin closed");
+                        info!("stdin closed");
                         break;
                     }
                 }
@@ -186,11 +1721,12 @@ async fn stdin_read(sender: mpsc::Sender<Vec<u8>>, ch: broadcast::Sender<()>) {
                     // If an error occurred while reading input
                     ErrorKind::UnexpectedEof => {
                         // If the error is an unexpected end-of-file
-                        log!("stdin closed");
+                        info!("stdin closed");
                         break;
                     }
                     _ => {
-                        log!("read failed: {:+?}", err);
+                        error!("read failed: {:+?}", err);
+                        code = 1;
                         break;
                     }
                 },
@@ -201,39 +1737,369 @@ async fn stdin_read(sender: mpsc::Sender<Vec<u8>>, ch: broadcast::Sender<()>) {
           }
         }
     }
-    log!("finish stdin read!");
+    info!("finish stdin read!");
+    code
+}
+
+// Runs the `clean` subcommand over the default output and every `[[output]]`,
+// returns the exit code
+fn clean(args: &Args) -> i32 {
+    let mut ok = rotate::clean_all(&args.rotate_options());
+    for output in args.outputs.iter() {
+        ok &= rotate::clean_all(&output.options);
+    }
+    match ok {
+        true => 0,
+        false => 1,
+    }
+}
+
+// The options of the output writing `path`, the top-level ones if none does
+fn options_for(args: &Args, path: &str) -> rotate::Options {
+    match args
+        .outputs
+        .iter()
+        .find(|output| output.options.path.as_deref() == Some(path))
+    {
+        Some(output) => output.options.clone(),
+        None => rotate::Options {
+            path: Some(path.to_string()),
+            ..args.rotate_options()
+        },
+    }
+}
+
+// Runs the `list` subcommand over `path`, returns the exit code
+fn list(args: &Args, path: &str) -> i32 {
+    match rotate::list_all(&options_for(args, path)) {
+        true => 0,
+        false => 1,
+    }
+}
+
+// Runs the `rotate-now` subcommand over `path`, with the options of the output
+// writing it if any, returns the exit code
+fn rotate_now(args: &Args, path: &str) -> i32 {
+    match rotate::rotate_now(&options_for(args, path)) {
+        true => 0,
+        false => 1,
+    }
+}
+
+// Returns the files of `dir` whose names match `pattern`, leaving out the rotated
+// files of the others, which the pattern may match too
+fn watched_files(dir: &str, pattern: &glob::Pattern) -> std::io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if pattern.matches(&name) && entry.file_type().is_ok_and(|t| t.is_file()) {
+            names.push(name);
+        }
+    }
+    names.sort();
+    // Sorted, the rotated files of a file come right after it
+    let mut files: Vec<String> = Vec::new();
+    for name in names {
+        if !files.iter().any(|file| name.starts_with(file.as_str())) {
+            files.push(name);
+        }
+    }
+    Ok(files
+        .into_iter()
+        .map(|name| Path::new(dir).join(name).to_string_lossy().into_owned())
+        .collect())
+}
+
+// Runs the `watch` subcommand over `dir` until we are stopped, returns the exit code.
+// The files are renamed as they are rotated, so the processes writing them must
+// reopen their files, e.g. on a signal of their own.
+async fn watch(args: &Args, dir: &str, pattern: &glob::Pattern, interval: Duration) -> i32 {
+    if !Path::new(dir).is_dir() {
+        eprintln!("\"{}\" is not a directory", dir);
+        return 1;
+    }
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        select! {
+            _ = ticker.tick() => {}
+            _ = &mut shutdown => return 0,
+        }
+        let files = match watched_files(dir, pattern) {
+            Ok(files) => files,
+            Err(err) => {
+                error!("failed to list \"{}\": {:+?}", dir, err);
+                continue;
+            }
+        };
+        for file in files {
+            let options = options_for(args, &file);
+            if rotate::is_due(&options) {
+                rotate::rotate_now(&options);
+            }
+        }
+    }
+}
+
+// Runs the `compress` subcommand over the default output and every `[[output]]`,
+// returns the exit code
+fn compress(args: &Args) -> i32 {
+    let codec = compress::Codec {
+        format: args.compress_format,
+        level: args.compress_level,
+    };
+    let mut ok = rotate::compress_all(&args.rotate_options(), codec);
+    for output in args.outputs.iter() {
+        ok &= rotate::compress_all(&output.options, output.options.compress.unwrap_or(codec));
+    }
+    match ok {
+        true => 0,
+        false => 1,
+    }
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
     let args = parse_args();
 
-    utils::set_debug(args.debug);
+    utils::set_level(args.debug, args.quiet);
+    rotate::set_dry_run(args.dry_run);
+    clock::set(match (args.timezone, args.utc) {
+        (Some(tz), _) => clock::Clock::Zone(tz),
+        (None, true) => clock::Clock::Utc,
+        (None, false) => clock::Clock::Local,
+    });
+    match args.command {
+        Some(Command::Compress) => exit(compress(&args)),
+        Some(Command::Clean) => exit(clean(&args)),
+        Some(Command::RotateNow { ref path }) => exit(rotate_now(&args, path)),
+        Some(Command::List { ref path }) => exit(list(&args, path)),
+        Some(Command::Watch {
+            ref dir,
+            ref pattern,
+            interval,
+        }) => exit(watch(&args, dir, pattern, interval).await),
+        None => {}
+    }
+    // Checked up front, as the spawn would only fail with a bare "not found"
+    if let Some(dir) = args.chdir.as_ref().filter(|_| !args.args.is_empty()) {
+        if !Path::new(dir).is_dir() {
+            eprintln!("working directory \"{}\" is not a directory", dir);
+            exit(1)
+        }
+    }
+    #[cfg(not(unix))]
+    if args.pty && !args.args.is_empty() {
+        eprintln!("--pty is only supported on Unix");
+        exit(1)
+    }
+    let credentials = match args.args.is_empty() {
+        true => None,
+        false => pm::resolve_credentials(args.user.as_deref(), args.group.as_deref())
+            .unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                exit(1)
+            }),
+    };
+    if let Some(signal) = args.postrotate_signal {
+        match args.args.is_empty() {
+            true => warn!("--postrotate-signal has no effect without a target process"),
+            false => pm::set_postrotate_signal(signal),
+        }
+    }
+    let metrics_listener = match args.metrics_addr {
+        Some(addr) => Some(metrics::bind(addr).await.unwrap_or_else(|err| {
+            eprintln!("failed to listen on metrics address {}: {}", addr, err);
+            exit(1)
+        })),
+        None => None,
+    };
+    worker::init(args.compress_jobs as usize);
 
-    let (sender, receiver) = mpsc::channel::<Vec<u8>>(64);
+    let (sender, receiver) = mpsc::channel::<Vec<u8>>(args.max_buffer as usize);
     let (ch, _) = broadcast::channel(3);
 
+    let options = args.rotate_options();
+    // The stderr of the target process gets its own channel and rotator if asked to
+    let (err_sender, err_receiver) = match &args.stderr_output {
+        Some(path) if !args.args.is_empty() => {
+            let (sender, receiver) = mpsc::channel::<Vec<u8>>(args.max_buffer as usize);
+            let options = args
+                .stderr_options
+                .clone()
+                .unwrap_or_else(|| rotate::Options {
+                    path: Some(path.clone()),
+                    ..options.clone()
+                });
+            (Some(sender), Some((options, receiver)))
+        }
+        _ => (None, None),
+    };
+    let outputs = args.outputs;
+    let max_buffer = args.max_buffer as usize;
     let src_handle = async {
-        if args.args.len() > 0 {
-            pm::spawn(args.args, sender, ch.clone()).await;
+        if !args.args.is_empty() {
+            // Tags can only go at the start of whole lines
+            let line_buffer =
+                (args.line_buffered || args.tag_source).then_some(args.max_line_length as usize);
+            let capture = pm::Capture {
+                line_buffer,
+                tags: args.tag_source.then_some(pm::Tags {
+                    stdout: args.stdout_tag,
+                    stderr: args.stderr_tag,
+                }),
+                delimiter: args.delimiter,
+            };
+            let senders = pm::Senders {
+                stdout: sender,
+                stderr: err_sender,
+            };
+            let supervision = pm::Supervision {
+                restart: args.restart,
+                restart_max: args.restart_max,
+                restart_window: args.restart_window,
+                term_timeout: Duration::from_secs(args.term_timeout),
+                // The child's output is drained within the time the queued data is
+                // written in
+                drain_timeout: (args.shutdown_timeout > 0)
+                    .then(|| Duration::from_secs(args.shutdown_timeout)),
+            };
+            let command = match args.shell {
+                true => pm::shell_command(&args.args),
+                false => args.args,
+            };
+            let launch = pm::Launch {
+                chdir: args.chdir,
+                env: args.child_env,
+                clear_env: args.clear_env,
+                env_remove: args.env_remove,
+                credentials,
+                pty: args.pty,
+            };
+            let res = pm::spawn(
+                command,
+                launch,
+                args.child_stdin,
+                capture,
+                senders,
+                supervision,
+                ch.clone(),
+            )
+            .await;
+            match res {
+                Ok(code) => code,
+                Err(err) => {
+                    eprintln!("{}", err.message);
+                    // Shut the rest down, the files get what was written so far
+                    let _ = ch.send(());
+                    err.code
+                }
+            }
         } else {
-            stdin_read(sender, ch.clone()).await;
-        };
+            stdin_read(sender, ch.clone()).await
+        }
+    };
+
+    let dst_handle = async {
+        if !outputs.is_empty() {
+            output::start(outputs, options, max_buffer, receiver, ch.clone()).await;
+        } else {
+            rotate::start(options, receiver, ch.clone()).await;
+        }
+    };
+
+    let err_handle = async {
+        if let Some((options, receiver)) = err_receiver {
+            rotate::start(options, receiver, ch.clone()).await;
+        }
+    };
+
+    let metrics_handle = async {
+        if let Some(listener) = metrics_listener {
+            metrics::serve(listener, ch.clone()).await;
+        }
     };
 
-    join!(
-        rotate::start(
-            args.output,
-            args.cut_mode,
-            args.file_size,
-            args.compress,
-            args.keep_days,
-            receiver,
-            ch.clone()
-        ),
+    let (_, _, code, _, _) = join!(
+        dst_handle,
+        err_handle,
         src_handle,
+        metrics_handle,
         signal(ch.clone())
     );
     drop(ch);
-    exit(0);
+    worker::finish();
+    utils::log_buffer_stats();
+    utils::flush_log();
+    exit(code);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_filter_regex_is_rejected_at_startup() {
+        assert!(Args::try_parse_from(["logrotate", "--include", "("]).is_err());
+        assert!(Args::try_parse_from(["logrotate", "--exclude", "[a-"]).is_err());
+        assert!(Args::try_parse_from(["logrotate", "--exclude", "health"]).is_ok());
+    }
+
+    #[test]
+    fn negative_keep_days_is_rejected() {
+        assert!(Args::try_parse_from(["logrotate", "--keep-days", "-1"]).is_err());
+        assert!(Args::try_parse_from(["logrotate", "--keep-days", "0"]).is_ok());
+    }
+
+    #[test]
+    fn negative_keep_days_in_the_config_is_rejected() {
+        let table: Table = toml::from_str("path = \"out\"\nkeep_days = -1").unwrap();
+        let defaults = Args::try_parse_from(["logrotate"])
+            .unwrap()
+            .rotate_options();
+        let codec = compress::Codec {
+            format: compress::Format::Gzip,
+            level: 6,
+        };
+        assert_eq!(
+            parse_output(&table, &defaults, codec).unwrap_err(),
+            "\"output.keep_days\" must be non-negative integer"
+        );
+
+        let table: Table = toml::from_str("keep_days = -1").unwrap();
+        let matches = Args::command().get_matches_from(["logrotate"]);
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        assert_eq!(
+            apply_config(&mut args, &table, &matches).unwrap_err(),
+            "\"keep_days\" must be non-negative integer"
+        );
+    }
+
+    #[test]
+    fn separator_must_keep_rotated_names_in_the_directory() {
+        assert_eq!(parse_separator("_"), Ok("_".to_string()));
+        assert!(parse_separator("").is_err());
+        assert!(parse_separator("/").is_err());
+    }
+
+    #[test]
+    fn workdir_sets_the_child_directory() {
+        let args = Args::try_parse_from(["logrotate", "--workdir", "/tmp", "--", "pwd"]).unwrap();
+        assert_eq!(args.chdir.as_deref(), Some("/tmp"));
+        assert_eq!(args.args, ["pwd"]);
+    }
+
+    #[test]
+    fn delimiter_is_a_single_byte() {
+        assert_eq!(parse_delimiter("\\n"), Ok(b'\n'));
+        assert_eq!(parse_delimiter("\\0"), Ok(0));
+        assert_eq!(parse_delimiter("\\x1e"), Ok(0x1e));
+        assert_eq!(parse_delimiter("|"), Ok(b'|'));
+        assert!(parse_delimiter("").is_err());
+        assert!(parse_delimiter("ab").is_err());
+        assert!(parse_delimiter("\\x1").is_err());
+    }
 }
@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::sync::broadcast;
+
+pub static BYTES_WRITTEN: AtomicU64 = AtomicU64::new(0); // The bytes written to log files
+pub static LINES_WRITTEN: AtomicU64 = AtomicU64::new(0); // The lines written to log files
+pub static ROTATIONS: AtomicU64 = AtomicU64::new(0); // The rotations performed
+pub static FILES_DELETED: AtomicU64 = AtomicU64::new(0); // The expired files removed
+pub static BYTES_SHREDDED: AtomicU64 = AtomicU64::new(0); // The bytes of expired files overwritten before removal
+pub static COMPRESS_FAILURES: AtomicU64 = AtomicU64::new(0); // The rotated files that failed to compress
+pub static BLOCKED_SENDS: AtomicU64 = AtomicU64::new(0); // The reads that found the channel full
+pub static BLOCKED_MICROS: AtomicU64 = AtomicU64::new(0); // The time producers waited on a full channel
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static FILE_SIZES: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new()); // The size of each active file
+
+#[inline]
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn add(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn get(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+// Records the current size of an active file, only tracked while the endpoint is enabled
+pub fn set_file_size(path: &str, size: u64) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Ok(mut sizes) = FILE_SIZES.lock() {
+        match sizes.get_mut(path) {
+            Some(cur) => *cur = size,
+            None => {
+                sizes.insert(path.to_string(), size);
+            }
+        }
+    }
+}
+
+// Renders the metrics in the Prometheus text exposition format
+fn render() -> String {
+    let counters = [
+        (
+            "logrotate_bytes_written_total",
+            "Bytes written to log files",
+            &BYTES_WRITTEN,
+        ),
+        (
+            "logrotate_lines_written_total",
+            "Lines written to log files",
+            &LINES_WRITTEN,
+        ),
+        (
+            "logrotate_rotations_total",
+            "Rotations performed",
+            &ROTATIONS,
+        ),
+        (
+            "logrotate_files_deleted_total",
+            "Expired files removed",
+            &FILES_DELETED,
+        ),
+        (
+            "logrotate_bytes_shredded_total",
+            "Bytes of expired files overwritten before removal",
+            &BYTES_SHREDDED,
+        ),
+        (
+            "logrotate_compress_failures_total",
+            "Rotated files that failed to compress",
+            &COMPRESS_FAILURES,
+        ),
+        (
+            "logrotate_blocked_sends_total",
+            "Reads that found the write channel full",
+            &BLOCKED_SENDS,
+        ),
+        (
+            "logrotate_blocked_microseconds_total",
+            "Time producers waited on a full write channel",
+            &BLOCKED_MICROS,
+        ),
+    ];
+    let mut out = String::new();
+    for (name, help, counter) in counters {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, get(counter));
+    }
+    let _ = writeln!(
+        out,
+        "# HELP logrotate_file_size_bytes Current size of the active log file"
+    );
+    let _ = writeln!(out, "# TYPE logrotate_file_size_bytes gauge");
+    if let Ok(sizes) = FILE_SIZES.lock() {
+        for (path, size) in sizes.iter() {
+            let path = path.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(
+                out,
+                "logrotate_file_size_bytes{{path=\"{}\"}} {}",
+                path, size
+            );
+        }
+    }
+    out
+}
+
+async fn respond(mut stream: TcpStream) {
+    // The request itself is not inspected, every path serves the metrics
+    let mut buf = [0u8; 1024];
+    if let Err(err) = stream.read(&mut buf).await {
+        error!("metrics read failed: {:+?}", err);
+        return;
+    }
+    let body = render();
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    if let Err(err) = stream.write_all((head + &body).as_bytes()).await {
+        error!("metrics write failed: {:+?}", err);
+    }
+    let _ = stream.shutdown().await;
+}
+
+// Listens on the metrics address, done at startup so that an address in use
+// fails before anything runs
+pub async fn bind(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    TcpListener::bind(addr).await
+}
+
+// Serves the metrics over HTTP until the shutdown broadcast fires
+pub async fn serve(listener: TcpListener, ch: broadcast::Sender<()>) {
+    ENABLED.store(true, Ordering::Relaxed);
+    if let Ok(addr) = listener.local_addr() {
+        info!("serving metrics on {}", addr);
+    }
+    let mut cr = ch.subscribe();
+    loop {
+        select! {
+            res = listener.accept() => match res {
+                Ok((stream, _)) => {
+                    tokio::spawn(respond(stream));
+                }
+                Err(err) => {
+                    error!("metrics accept failed: {:+?}", err);
+                }
+            },
+            _ = cr.recv() => {
+                break;
+            }
+        }
+    }
+    info!("closed metrics server");
+}
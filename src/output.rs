@@ -0,0 +1,111 @@
+use regex::bytes::Regex;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+
+use crate::rotate;
+use crate::utils;
+
+// A named destination configured by an `[[output]]` section
+#[derive(Clone, Debug)]
+pub(crate) struct Output {
+    pub name: String,             // The name used in diagnostics, defaults to the path
+    pub pattern: Option<Regex>, // Lines matching this pattern are written here, `None` matches all
+    pub options: rotate::Options, // The rotation settings of this destination
+}
+
+struct Route {
+    pattern: Option<Regex>,
+    sender: mpsc::Sender<Vec<u8>>,
+    batch: Vec<u8>,
+}
+
+// Picks the destination for a line: the first output whose pattern matches wins,
+// lines that match nothing go to the last route, which is the default output
+fn route_index(routes: &[Route], line: &[u8], delimiter: u8) -> usize {
+    routes
+        .iter()
+        .position(|r| {
+            r.pattern
+                .as_ref()
+                .is_none_or(|p| p.is_match(utils::line_content(line, delimiter)))
+        })
+        .unwrap_or(routes.len() - 1)
+}
+
+// Sends the lines collected for each route during one read
+async fn send_batches(routes: &mut [Route]) {
+    for route in routes.iter_mut() {
+        if route.batch.is_empty() {
+            continue;
+        }
+        let batch = std::mem::take(&mut route.batch);
+        if let Err(err) = route.sender.send(batch).await {
+            error!("route write failed: {:+?}", err);
+        }
+    }
+}
+
+// Splits the incoming stream into lines and dispatches each line to the rotator of
+// the matching output. Every output runs its own `rotate::start` task, the default
+// output receives the lines that no `[[output]]` pattern matched.
+pub async fn start(
+    outputs: Vec<Output>,
+    default: rotate::Options,
+    buffer: usize, // The capacity of the channel of each output
+    mut receiver: mpsc::Receiver<Vec<u8>>,
+    ch: broadcast::Sender<()>,
+) {
+    let max_line = default.max_line_length;
+    let delimiter = default.delimiter;
+    let mut routes = Vec::new();
+    let mut joins = Vec::new();
+    for output in outputs {
+        info!("output \"{}\": {:?}", output.name, output.options.path);
+        let (sender, rx) = mpsc::channel::<Vec<u8>>(buffer);
+        joins.push(tokio::spawn(rotate::start(output.options, rx, ch.clone())));
+        routes.push(Route {
+            pattern: output.pattern,
+            sender,
+            batch: Vec::new(),
+        });
+    }
+    let (sender, rx) = mpsc::channel::<Vec<u8>>(buffer);
+    joins.push(tokio::spawn(rotate::start(default, rx, ch.clone())));
+    routes.push(Route {
+        pattern: None,
+        sender,
+        batch: Vec::new(),
+    });
+
+    let mut tail: Vec<u8> = Vec::new();
+    while let Some(data) = receiver.recv().await {
+        tail.extend_from_slice(&data);
+        let end = tail
+            .iter()
+            .rposition(|&x| x == delimiter)
+            .map_or(0, |index| index + 1);
+        let mut rest = tail.split_off(end);
+        let long = utils::split_long_line(&mut rest, max_line, delimiter);
+        for line in
+            utils::Lines::new(tail.as_slice(), delimiter).chain(long.iter().map(Vec::as_slice))
+        {
+            let index = route_index(&routes, line, delimiter);
+            routes[index].batch.extend_from_slice(line);
+        }
+        tail = rest;
+        send_batches(&mut routes).await;
+    }
+    if !tail.is_empty() {
+        let index = route_index(&routes, &tail, delimiter);
+        routes[index].batch.append(&mut tail);
+        send_batches(&mut routes).await;
+    }
+    drop(routes);
+
+    for j in joins {
+        if let Err(err) = j.await {
+            error!("join failed: {:+?}", err);
+        }
+    }
+    info!("closed output router");
+}
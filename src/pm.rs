@@ -1,77 +1,1238 @@
 extern crate libc;
 
-use std::process::Stdio;
-use tokio::io::{AsyncRead, AsyncReadExt};
-use tokio::process::Command;
+use chrono::Utc;
+use clap::ValueEnum;
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{stdin, AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin, Command};
 use tokio::select;
 use tokio::sync::broadcast;
 use tokio::sync::mpsc;
+use tokio::time;
 
-async fn handle_out(mut out: impl AsyncRead + Unpin, name: &str, sender: mpsc::Sender<Vec<u8>>) {
+use crate::clock;
+use crate::utils;
+
+#[derive(Clone, Debug, ValueEnum)]
+pub(crate) enum StdinMode {
+    Inherit, // The child reads our stdin directly
+    Null,    // The child's stdin is empty, it sees EOF right away
+    Pipe,    // Our stdin is copied into the child's stdin
+}
+
+// How the outputs of the child are passed on
+#[derive(Clone, Debug)]
+pub(crate) struct Capture {
+    pub line_buffer: Option<usize>, // The longest line held back to send whole lines, 0 is unlimited
+    pub tags: Option<Tags>,         // What each line is prefixed with, by the output it came from
+    pub delimiter: u8,              // The byte lines end with
+}
+
+// The prefixes telling the lines of stdout and stderr apart
+#[derive(Clone, Debug)]
+pub(crate) struct Tags {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+// Where and with which environment the child runs
+#[derive(Debug)]
+pub(crate) struct Launch {
+    pub chdir: Option<String>,      // The working directory, ours if not set
+    pub env: Vec<(String, String)>, // The variables set on top of the environment
+    pub clear_env: bool,            // Whether our environment is left out
+    pub env_remove: Vec<String>,    // The variables taken out of our environment
+    pub credentials: Option<Credentials>, // Who the child runs as, the same as us if not set
+    pub pty: bool,                  // Whether the child writes to a pseudo-terminal
+}
+
+// The size the pseudo-terminal of the child reports, a common terminal's
+const PTY_ROWS: u16 = 24;
+const PTY_COLS: u16 = 80;
+
+// The user and groups the child runs as
+#[derive(Debug)]
+pub(crate) struct Credentials {
+    pub uid: Option<u32>, // The user, ours if only the group changes
+    pub gid: u32,         // The primary group
+    pub groups: Vec<u32>, // The supplementary groups
+}
+
+// Looks up a user by name or uid, returns its name (if known), uid and primary group
+#[cfg(unix)]
+fn lookup_user(user: &str) -> Result<(Option<String>, u32, u32), String> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let numeric = user.parse::<u32>().ok();
+    let name = std::ffi::CString::new(user).map_err(|_| format!("invalid user \"{}\"", user))?;
+    let ret = unsafe {
+        match numeric {
+            Some(uid) => libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result),
+            None => libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            ),
+        }
+    };
+    if ret != 0 {
+        let err = std::io::Error::from_raw_os_error(ret);
+        return Err(format!("failed to look up user \"{}\": {}", user, err));
+    }
+    match (result.is_null(), numeric) {
+        (false, _) => {
+            let name = unsafe { std::ffi::CStr::from_ptr(pwd.pw_name) };
+            Ok((
+                Some(name.to_string_lossy().into_owned()),
+                pwd.pw_uid,
+                pwd.pw_gid,
+            ))
+        }
+        // A uid without an account is still usable, its group is then the same number
+        (true, Some(uid)) => Ok((None, uid, uid)),
+        (true, None) => Err(format!("user \"{}\" does not exist", user)),
+    }
+}
+
+// Looks up a group by name or gid
+#[cfg(unix)]
+fn lookup_group(group: &str) -> Result<u32, String> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(gid);
+    }
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::group = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let name = std::ffi::CString::new(group).map_err(|_| format!("invalid group \"{}\"", group))?;
+    let ret = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 {
+        let err = std::io::Error::from_raw_os_error(ret);
+        return Err(format!("failed to look up group \"{}\": {}", group, err));
+    }
+    match result.is_null() {
+        false => Ok(grp.gr_gid),
+        true => Err(format!("group \"{}\" does not exist", group)),
+    }
+}
+
+// Returns the groups of a user as initgroups would set them, `gid` included
+#[cfg(unix)]
+fn user_groups(name: &str, gid: u32) -> Vec<u32> {
+    let Ok(name) = std::ffi::CString::new(name) else {
+        return vec![gid];
+    };
+    let mut groups = vec![0 as libc::gid_t; 64];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let ret = unsafe {
+            libc::getgrouplist(
+                name.as_ptr(),
+                gid as _,
+                groups.as_mut_ptr() as *mut _,
+                &mut count,
+            )
+        };
+        if ret >= 0 {
+            groups.truncate(count as usize);
+            return groups;
+        }
+        // Too small, `count` is then the size needed on most systems
+        let len = (count as usize).max(groups.len() * 2);
+        if len > 65536 {
+            return vec![gid];
+        }
+        groups.resize(len, 0);
+    }
+}
+
+// Resolves the user and group the child is asked to run as. Changing them needs
+// root, unless they are ours already, in which case nothing is changed.
+#[cfg(unix)]
+pub fn resolve_credentials(
+    user: Option<&str>,
+    group: Option<&str>,
+) -> Result<Option<Credentials>, String> {
+    let user = user.map(lookup_user).transpose()?;
+    let group = group.map(lookup_group).transpose()?;
+    let credentials = match (user, group) {
+        (None, None) => return Ok(None),
+        (Some((name, uid, primary)), group) => {
+            let gid = group.unwrap_or(primary);
+            let groups = match name {
+                Some(name) => user_groups(&name, gid),
+                None => vec![gid],
+            };
+            Credentials {
+                uid: Some(uid),
+                gid,
+                groups,
+            }
+        }
+        (None, Some(gid)) => Credentials {
+            uid: None,
+            gid,
+            groups: vec![gid],
+        },
+    };
+    let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    if euid == 0 {
+        return Ok(Some(credentials));
+    }
+    if credentials.uid.is_none_or(|uid| uid == euid) && credentials.gid == egid {
+        return Ok(None);
+    }
+    Err("running the target process as another user or group needs root".to_string())
+}
+
+#[cfg(not(unix))]
+pub fn resolve_credentials(
+    user: Option<&str>,
+    group: Option<&str>,
+) -> Result<Option<Credentials>, String> {
+    match (user, group) {
+        (None, None) => Ok(None),
+        _ => Err("--user and --group are only supported on Unix".to_string()),
+    }
+}
+
+// Switches the child to the given credentials right before it executes. This is
+// done here rather than with Command::uid/gid, as those clear the supplementary
+// groups and run before the pre_exec hooks, too late to set them. Only
+// async-signal-safe calls are made, everything was looked up in the parent.
+#[cfg(unix)]
+fn set_credentials(command: &mut Command, credentials: &Credentials) {
+    let uid = credentials.uid;
+    let gid = credentials.gid;
+    let groups = credentials.groups.clone();
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setgroups(groups.len() as _, groups.as_ptr()) != 0
+                || libc::setgid(gid) != 0
+                || uid.is_some_and(|uid| libc::setuid(uid) != 0)
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+// Where the outputs of the child are sent, stderr goes along with stdout unless it
+// has a channel of its own
+pub(crate) struct Senders {
+    pub stdout: mpsc::Sender<Vec<u8>>,
+    pub stderr: Option<mpsc::Sender<Vec<u8>>>,
+}
+
+// Copies our stdin into the child's stdin. The child's stdin is closed once ours
+// reaches EOF, so the child sees the end of input too.
+async fn forward_stdin(mut input: ChildStdin, ch: broadcast::Sender<()>) {
+    let mut stdin = stdin();
+    let mut cr = ch.subscribe();
+    let mut buf = vec![0u8; 8192];
+    loop {
+        select! {
+            res = stdin.read(&mut buf) => match res {
+                Ok(0) => {
+                    info!("stdin closed");
+                    break;
+                }
+                Ok(size) => {
+                    match input.write_all(&buf[..size]).await {
+                        Ok(_) => {}
+                        // The child stopped reading, which is how a pipe ends early
+                        Err(err) if err.kind() == std::io::ErrorKind::BrokenPipe => {
+                            info!("child stdin closed");
+                            break;
+                        }
+                        Err(err) => {
+                            error!("child stdin write failed: {:+?}", err);
+                            break;
+                        }
+                    }
+                }
+                Err(err) => {
+                    error!("read failed: {:+?}", err);
+                    break;
+                }
+            },
+            _ = cr.recv() => {
+                break;
+            }
+        }
+    }
+    drop(input);
+    info!("finish stdin forward!");
+}
+
+// Reads one output of the child and sends it on. With a line buffer, only whole
+// lines are sent, so that the lines of stdout and stderr never interleave; a line
+// longer than the buffer's maximum (0 is unlimited) is split like the rotator
+// does. A last line without a newline at EOF is sent with one added, otherwise it
+// would be joined with the next line of the other output. Tagging prefixes every
+// line sent with `tag`, it needs the line buffer so that a line split across reads
+// is tagged once.
+async fn handle_out(
+    mut out: impl AsyncRead + Unpin,
+    name: &str,
+    line_buffer: Option<usize>,
+    delimiter: u8,
+    tag: Option<Vec<u8>>,
+    sender: mpsc::Sender<Vec<u8>>,
+) {
+    let mut tail = Vec::new(); // The partial line held back in line-buffered mode
     loop {
         let mut buf = Vec::new();
         select! {
             res = out.read_buf(&mut buf) => {// Read the output from the child process
-                if buf.len()>0 {
-                  if let Err(err) = sender.send(buf).await { // Send the output to the receiver
-                      log!("{} write failed: {:+?}", name, err);
+                if let Some(max) = line_buffer {
+                    buf = take_lines(&mut tail, buf, max, delimiter);
+                }
+                if let Some(tag) = &tag {
+                    buf = tag_lines(&buf, tag, delimiter);
+                }
+                if !buf.is_empty() {
+                  if let Err(err) = utils::send(&sender, buf, name).await { // Send the output to the receiver
+                      error!("{} write failed: {:+?}", name, err);
                   }
                 }
                 match res {
                     Ok(size) => {
                         if size == 0 {
-                          log!("{} closed", name);
+                          info!("{} closed", name);
                           break;
                         }
                     },
+                    // The master side of a pseudo-terminal fails with EIO once the
+                    // child and everything it started closed the terminal, its EOF
+                    #[cfg(unix)]
+                    Err(err) if err.raw_os_error() == Some(libc::EIO) => {
+                        info!("{} closed", name);
+                        break;
+                    }
                     Err(err) => {
-                        log!("{} read failed: {:+?}", name, err);
+                        error!("{} read failed: {:+?}", name, err);
                         break;
                     }
                 }
             }
         }
     }
+    if !tail.is_empty() {
+        tail.push(delimiter);
+        if let Some(tag) = &tag {
+            tail = tag_lines(&tail, tag, delimiter);
+        }
+        if let Err(err) = utils::send(&sender, tail, name).await {
+            error!("{} write failed: {:+?}", name, err);
+        }
+    }
+}
+
+// Appends `buf` to the partial line in `tail` and returns the whole lines, the new
+// partial line is left in `tail`
+fn take_lines(tail: &mut Vec<u8>, buf: Vec<u8>, max: usize, delimiter: u8) -> Vec<u8> {
+    let mut data = match tail.is_empty() {
+        true => buf,
+        false => {
+            let mut data = std::mem::take(tail);
+            data.extend_from_slice(&buf);
+            data
+        }
+    };
+    let end = data
+        .iter()
+        .rposition(|&x| x == delimiter)
+        .map_or(0, |index| index + 1);
+    *tail = data.split_off(end);
+    for line in utils::split_long_line(tail, max, delimiter) {
+        data.extend_from_slice(&line);
+    }
+    data
+}
+
+// Prefixes each of the whole lines in `buf` with `tag`
+fn tag_lines(buf: &[u8], tag: &[u8], delimiter: u8) -> Vec<u8> {
+    let mut tagged = Vec::with_capacity(buf.len() + tag.len() * 4);
+    for line in utils::Lines::new(buf, delimiter) {
+        tagged.extend_from_slice(tag);
+        tagged.extend_from_slice(line);
+    }
+    tagged
+}
+
+// The signal the child is sent on shutdown: the one we were stopped with, SIGTERM if
+// the shutdown has another cause
+static SHUTDOWN_SIGNAL: AtomicI32 = AtomicI32::new(libc::SIGTERM);
+
+pub fn set_shutdown_signal(signal: i32) {
+    SHUTDOWN_SIGNAL.store(signal, Ordering::Relaxed);
+}
+
+// The signal the child is sent after each rotation, 0 sends none
+static POSTROTATE_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+// The pid of the running child, 0 while there is none, as between an exit and
+// the restart
+static CHILD_PID: AtomicI32 = AtomicI32::new(0);
+
+pub fn set_postrotate_signal(signal: i32) {
+    POSTROTATE_SIGNAL.store(signal, Ordering::Relaxed);
+}
+
+// Sends the post-rotate signal to the child, if one is set. A rotation while the
+// child is down for a restart sends nothing, the restarted child opens its files
+// afresh anyway.
+pub fn notify_rotated() {
+    let signal = POSTROTATE_SIGNAL.load(Ordering::Relaxed);
+    if signal == 0 {
+        return;
+    }
+    let pid = CHILD_PID.load(Ordering::Relaxed);
+    if pid == 0 {
+        info!(
+            "no child process to send signal {} to after rotation",
+            signal
+        );
+        return;
+    }
+    info!(
+        "sending signal {} to the child process {} after rotation",
+        signal, pid
+    );
+    #[cfg(unix)]
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } != 0 {
+        warn!(
+            "failed to send signal {}: {:+?}",
+            signal,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+// Parses a signal given by name, with or without the SIG prefix, or by number
+#[cfg(unix)]
+pub fn parse_signal(name: &str) -> Result<i32, String> {
+    if let Ok(signal) = name.parse::<i32>() {
+        return match signal > 0 {
+            true => Ok(signal),
+            false => Err(format!("invalid signal \"{}\"", name)),
+        };
+    }
+    let upper = name.to_ascii_uppercase();
+    let signal = match upper.strip_prefix("SIG").unwrap_or(&upper) {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "ALRM" => libc::SIGALRM,
+        "TERM" => libc::SIGTERM,
+        "CONT" => libc::SIGCONT,
+        "WINCH" => libc::SIGWINCH,
+        _ => return Err(format!("unknown signal \"{}\"", name)),
+    };
+    Ok(signal)
+}
+
+#[cfg(not(unix))]
+pub fn parse_signal(name: &str) -> Result<i32, String> {
+    Err(format!(
+        "signals such as \"{}\" are only supported on Unix",
+        name
+    ))
+}
+
+// Forwards the shutdown signal to the child and waits for it, then kills it if it
+// is still running after `timeout`. Its output keeps being read in the meantime.
+// Returns the exit code of the child.
+async fn terminate(child: &mut Child, timeout: Duration) -> i32 {
+    #[cfg(unix)]
+    match child.id() {
+        Some(pid) => {
+            let signal = SHUTDOWN_SIGNAL.load(Ordering::Relaxed);
+            info!("sending signal {} to the child process {}", signal, pid);
+            if unsafe { libc::kill(pid as libc::pid_t, signal) } != 0 {
+                error!(
+                    "failed to send signal {}: {:+?}",
+                    signal,
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+        // Already reaped, its status is kept
+        None => {
+            return match child.try_wait() {
+                Ok(Some(status)) => exit_code(status),
+                Ok(None) => 0,
+                Err(err) => {
+                    error!("wait failed: {:+?}", err);
+                    1
+                }
+            }
+        }
+    }
+    #[cfg(unix)]
+    match time::timeout(timeout, child.wait()).await {
+        Ok(Ok(status)) => {
+            info!("child process exited: {}", status);
+            return exit_code(status);
+        }
+        Ok(Err(err)) => {
+            error!("wait failed: {:+?}", err);
+            return 1;
+        }
+        Err(_) => warn!(
+            "child process still running after {:?}, killing it",
+            timeout
+        ),
+    }
+    #[cfg(not(unix))]
+    let _ = timeout;
+    if let Err(err) = child.kill().await {
+        error!("failed to kill the child process: {:+?}", err);
+        return 1;
+    }
+    match child.wait().await {
+        Ok(status) => exit_code(status),
+        Err(_) => 1,
+    }
+}
+
+// Wraps the command into a shell invocation, the arguments are joined with spaces
+// and interpreted by the shell: quotes, pipes, globs and variables all apply, so
+// anything taken from untrusted input can run arbitrary commands
+pub fn shell_command(args: &[String]) -> Vec<String> {
+    let line = args.join(" ");
+    #[cfg(windows)]
+    return vec!["cmd".to_string(), "/C".to_string(), line];
+    #[cfg(not(windows))]
+    return vec!["sh".to_string(), "-c".to_string(), line];
+}
+
+// When the child is started again after it exits
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub(crate) enum Restart {
+    Never,     // We exit along with the child
+    OnFailure, // The child is restarted when it exits with a non-zero code
+    Always,    // The child is restarted whatever its exit code
+}
+
+// How the child is looked after
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Supervision {
+    pub restart: Restart,                // When the child is restarted
+    pub restart_max: u32,                // The restarts in a row allowed, 0 is unlimited
+    pub restart_window: Duration, // How long the child must stay up to reset the count, 0 never does
+    pub term_timeout: Duration,   // How long the child is given to exit on shutdown
+    pub drain_timeout: Option<Duration>, // How long its output is read on shutdown, see OUTPUT_DRAIN_TIMEOUT
+}
+
+// The wait before the first restart, doubled on each restart in a row
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+
+// The longest wait before a restart. A child that ran at least this long was up
+// for good, the wait goes back to RESTART_BACKOFF.
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+// How long the child's output is still read on shutdown after it exited, unless
+// the shutdown has a timeout of its own
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Opens a pseudo-terminal, returns its master and slave sides. Newlines written to
+// the slave are passed on as is rather than turned into \r\n, and neither side is
+// inherited by the processes we start besides where it is set up as their stdio.
+#[cfg(unix)]
+fn open_pty() -> std::io::Result<(OwnedFd, OwnedFd)> {
+    let (mut master, mut slave) = (0, 0);
+    let mut size = libc::winsize {
+        ws_row: PTY_ROWS,
+        ws_col: PTY_COLS,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let ret = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null_mut::<libc::termios>(),
+            std::ptr::addr_of_mut!(size), // Mutable on some systems, const on others
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let (master, slave) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+    for fd in [&master, &slave] {
+        if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    let mut term: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(slave.as_raw_fd(), &mut term) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    term.c_oflag &= !libc::ONLCR;
+    if unsafe { libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &term) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok((master, slave))
+}
+
+// Sets the child's stdout up on a new pseudo-terminal, along with its stderr unless
+// that has an output of its own, and returns the master side the output is read
+// from. The child gets a session of its own with the terminal as its controlling
+// terminal, like a shell would give it.
+#[cfg(unix)]
+fn attach_pty(command: &mut Command, separate_stderr: bool) -> std::io::Result<std::fs::File> {
+    let (master, slave) = open_pty()?;
+    if !separate_stderr {
+        command.stderr(Stdio::from(slave.try_clone()?));
+    }
+    command.stdout(Stdio::from(slave));
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() < 0 || libc::ioctl(1, libc::TIOCSCTTY as _, 0) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(std::fs::File::from(master))
+}
+
+// Why the target process could not be started
+#[derive(Debug)]
+pub(crate) struct SpawnError {
+    pub message: String, // What failed, with the command and the OS error
+    pub code: i32,       // The exit code we exit with, see `SpawnError::new`
+}
+
+impl SpawnError {
+    // Exits like shells do: 127 when the command is not found, 126 when it was
+    // found but can't be run, 1 on other failures such as a fork failing
+    fn new(program: &str, err: std::io::Error) -> Self {
+        let code = match err.kind() {
+            std::io::ErrorKind::NotFound => 127,
+            std::io::ErrorKind::PermissionDenied => 126,
+            _ if err.raw_os_error() == Some(libc::ENOEXEC) => 126,
+            _ => 1,
+        };
+        SpawnError {
+            message: format!("failed to start \"{}\": {}", program, err),
+            code,
+        }
+    }
 }
 
-pub async fn spawn(args: Vec<String>, sender: mpsc::Sender<Vec<u8>>, ch: broadcast::Sender<()>) {
-    let mut command = Command::new(args[0].clone());
+// Builds the command running the target process, along with the master side of its
+// pseudo-terminal if it writes to one
+fn command(
+    args: &[String],
+    launch: &Launch,
+    stdin_mode: &StdinMode,
+    separate_stderr: bool,
+) -> Result<(Command, Option<std::fs::File>), SpawnError> {
+    let Some(program) = args.first() else {
+        return Err(SpawnError {
+            message: "no command to run".to_string(),
+            code: 1,
+        });
+    };
+    let mut command = Command::new(program);
     command.args(&args[1..]);
-    command.stdin(Stdio::inherit());
+    // Only the child changes directory, paths we open stay relative to ours
+    if let Some(dir) = &launch.chdir {
+        command.current_dir(dir);
+    }
+    if launch.clear_env {
+        command.env_clear();
+    }
+    for key in &launch.env_remove {
+        command.env_remove(key);
+    }
+    command.envs(launch.env.iter().cloned());
+    #[cfg(unix)]
+    if let Some(credentials) = &launch.credentials {
+        set_credentials(&mut command, credentials);
+    }
+    match stdin_mode {
+        StdinMode::Inherit => command.stdin(Stdio::inherit()),
+        StdinMode::Null => command.stdin(Stdio::null()),
+        StdinMode::Pipe => command.stdin(Stdio::piped()),
+    };
     command.stdout(Stdio::piped());
     command.stderr(Stdio::piped());
+    #[cfg(unix)]
+    if launch.pty {
+        let master = attach_pty(&mut command, separate_stderr).map_err(|err| SpawnError {
+            message: format!("failed to open a pseudo-terminal: {}", err),
+            code: 1,
+        })?;
+        return Ok((command, Some(master)));
+    }
+    #[cfg(not(unix))]
+    let _ = separate_stderr;
+    Ok((command, None))
+}
 
-    let mut child = command.spawn().expect("failed to spawn child process");
+// Runs the target process once, capturing its output, until it exits or we are
+// shut down. Returns its exit code and whether we were shut down, or why it could
+// not be started.
+async fn run_once(
+    mut command: Command,
+    pty: Option<std::fs::File>,
+    capture: &Capture,
+    senders: &Senders,
+    supervision: &Supervision,
+    cr: &mut broadcast::Receiver<()>,
+    ch: &broadcast::Sender<()>,
+) -> Result<(i32, bool), SpawnError> {
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            let program = command
+                .as_std()
+                .get_program()
+                .to_string_lossy()
+                .into_owned();
+            return Err(SpawnError::new(&program, err));
+        }
+    };
+    CHILD_PID.store(child.id().map_or(0, |pid| pid as i32), Ordering::Relaxed);
+    // Our copies of the terminal's slave side go with the command, so that reading
+    // the master ends once the child and whatever it started are gone
+    drop(command);
     let mut joins = Vec::new();
 
+    // Not joined: reading our stdin may never finish, it is aborted once the child exits
+    let forward = child
+        .stdin
+        .take()
+        .map(|input| tokio::spawn(forward_stdin(input, ch.clone())));
+
+    if let Some(master) = pty {
+        let sender = senders.stdout.clone();
+        let tag = capture.tags.as_ref().map(|t| t.stdout.clone().into_bytes());
+        let join = tokio::spawn(handle_out(
+            tokio::fs::File::from_std(master),
+            "stdout",
+            capture.line_buffer,
+            capture.delimiter,
+            tag,
+            sender,
+        ));
+        joins.push(join);
+    }
+
     if let Some(stdout) = child.stdout {
-        let sender = sender.clone();
-        let join = tokio::spawn(handle_out(stdout, "stdout", sender));
+        let sender = senders.stdout.clone();
+        let tag = capture.tags.as_ref().map(|t| t.stdout.clone().into_bytes());
+        let join = tokio::spawn(handle_out(
+            stdout,
+            "stdout",
+            capture.line_buffer,
+            capture.delimiter,
+            tag,
+            sender,
+        ));
         joins.push(join);
         child.stdout = None;
     }
 
     if let Some(stderr) = child.stderr {
-        let sender = sender.clone();
-        let join = tokio::spawn(handle_out(stderr, "stderr", sender));
+        let sender = senders.stderr.clone().unwrap_or(senders.stdout.clone());
+        let tag = capture.tags.as_ref().map(|t| t.stderr.clone().into_bytes());
+        let join = tokio::spawn(handle_out(
+            stderr,
+            "stderr",
+            capture.line_buffer,
+            capture.delimiter,
+            tag,
+            sender,
+        ));
         joins.push(join);
         child.stderr = None;
     }
 
-    let mut cr = ch.subscribe();
-
+    let mut code = 0;
+    let mut stopped = false;
     select! {
-        _ = child.wait() => {
-            log!("child process exited");
+        res = child.wait() => match res {
+            Ok(status) => {
+                info!("child process exited: {}", status);
+                code = exit_code(status);
+            }
+            Err(err) => {
+                error!("wait failed: {:+?}", err);
+            }
         },
-        _ = cr.recv() => {}
+        _ = cr.recv() => {
+            code = terminate(&mut child, supervision.term_timeout).await;
+            stopped = true;
+        }
     }
-    for j in joins {
-        match j.await {
-            Err(err) => {
-                log!("join failed: {:+?}", err);
+    CHILD_PID.store(0, Ordering::Relaxed);
+    if let Some(forward) = forward {
+        forward.abort();
+    }
+    // Once the child is gone its output is read to the end, but on shutdown
+    // something it started may still hold the pipes open, it is given
+    // the drain timeout before the rest of its output is dropped
+    let drain_timeout = supervision.drain_timeout.unwrap_or(OUTPUT_DRAIN_TIMEOUT);
+    let deadline = time::Instant::now() + drain_timeout;
+    let mut dropped = false;
+    for mut j in joins {
+        let res = if stopped {
+            match time::timeout_at(deadline, &mut j).await {
+                Ok(res) => res,
+                Err(_) => {
+                    if !dropped {
+                        warn!(
+                            "child output still open after {:?}, dropping the rest",
+                            drain_timeout
+                        );
+                        dropped = true;
+                    }
+                    j.abort();
+                    continue;
+                }
             }
-            _ => {}
+        } else {
+            j.await
+        };
+        if let Err(err) = res {
+            error!("join failed: {:+?}", err);
+        }
+    }
+    Ok((code, stopped))
+}
+
+// Runs the target process, capturing its output, and returns the exit code to
+// exit with: the child's, 128 + the signal number if a signal ended it. On
+// shutdown the child is terminated first, see `terminate`. Depending on the
+// restart policy the child is started again after it exits, with a backoff, and
+// a line marking the restart is written to the log. A child that can't be started,
+// on a restart too, ends it with the error.
+pub async fn spawn(
+    args: Vec<String>,
+    launch: Launch,
+    stdin_mode: StdinMode,
+    capture: Capture,
+    senders: Senders,
+    supervision: Supervision,
+    ch: broadcast::Sender<()>,
+) -> Result<i32, SpawnError> {
+    let mut cr = ch.subscribe();
+    let mut restarts = 0;
+    let mut backoff = RESTART_BACKOFF;
+    loop {
+        let started = Instant::now();
+        let (command, pty) = command(&args, &launch, &stdin_mode, senders.stderr.is_some())?;
+        let (code, stopped) =
+            run_once(command, pty, &capture, &senders, &supervision, &mut cr, &ch).await?;
+        let restart = match supervision.restart {
+            Restart::Never => false,
+            Restart::OnFailure => code != 0,
+            Restart::Always => true,
+        };
+        // A child stopped by our shutdown, with the signal forwarded to it, is never
+        // restarted
+        if stopped || !restart {
+            return Ok(code);
+        }
+        let window = supervision.restart_window;
+        if !window.is_zero() && started.elapsed() >= window {
+            restarts = 0;
+            backoff = RESTART_BACKOFF;
+        }
+        if supervision.restart_max > 0 && restarts >= supervision.restart_max {
+            error!("child process restarted {} times, giving up", restarts);
+            return Ok(code);
+        }
+        restarts += 1;
+        if started.elapsed() >= RESTART_BACKOFF_MAX {
+            backoff = RESTART_BACKOFF;
+        }
+        let mut line = format!(
+            "[logrotate] {} child process exited with code {}, restarting in {:?} (attempt {})",
+            clock::get().format(Utc::now(), "%Y-%m-%d %H:%M:%S%.3f %z"),
+            code,
+            backoff,
+            restarts
+        )
+        .into_bytes();
+        line.push(capture.delimiter);
+        if let Err(err) = utils::send(&senders.stdout, line, "restart").await {
+            error!("restart mark write failed: {:+?}", err);
         }
+        select! {
+            _ = time::sleep(backoff) => {}
+            _ = cr.recv() => return Ok(code),
+        }
+        backoff = (backoff * 2).min(RESTART_BACKOFF_MAX);
+    }
+}
+
+// Converts the child's exit status to our exit code, a child killed by a signal
+// gives 128 + the signal number like shells do
+fn exit_code(status: ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        return code;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn launch() -> Launch {
+        Launch {
+            chdir: None,
+            env: Vec::new(),
+            clear_env: false,
+            env_remove: Vec::new(),
+            credentials: None,
+            pty: false,
+        }
+    }
+
+    fn capture() -> Capture {
+        Capture {
+            line_buffer: Some(0),
+            tags: None,
+            delimiter: b'\n',
+        }
+    }
+
+    fn supervision(restart: Restart) -> Supervision {
+        Supervision {
+            restart,
+            restart_max: 0,
+            restart_window: Duration::ZERO,
+            term_timeout: Duration::from_secs(5),
+            drain_timeout: None,
+        }
+    }
+
+    // Runs `script` with the shell until it exits for good, returns the result of
+    // `spawn` and everything the child wrote
+    async fn run(
+        script: &str,
+        launch: Launch,
+        capture: Capture,
+        supervision: Supervision,
+    ) -> (Result<i32, SpawnError>, Vec<u8>) {
+        let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(16);
+        let collect = tokio::spawn(async move {
+            let mut output = Vec::new();
+            while let Some(data) = receiver.recv().await {
+                output.extend_from_slice(&data);
+            }
+            output
+        });
+        let senders = Senders {
+            stdout: sender,
+            stderr: None,
+        };
+        let (ch, _) = broadcast::channel(3);
+        let args = shell_command(&[script.to_string()]);
+        let res = spawn(
+            args,
+            launch,
+            StdinMode::Null,
+            capture,
+            senders,
+            supervision,
+            ch,
+        )
+        .await;
+        (res, collect.await.unwrap())
+    }
+
+    // The exit code of `script` run once with the shell
+    async fn exit_code_of(script: &str) -> i32 {
+        let (res, _) = run(script, launch(), capture(), supervision(Restart::Never)).await;
+        res.unwrap()
+    }
+
+    #[tokio::test]
+    async fn exit_code_of_the_child_is_passed_on() {
+        assert_eq!(exit_code_of("exit 0").await, 0);
+        assert_eq!(exit_code_of("exit 3").await, 3);
+        assert_eq!(exit_code_of("kill -KILL $$").await, 128 + libc::SIGKILL);
+    }
+
+    // The exit code of a program that can't be started
+    async fn start_error(program: &str) -> i32 {
+        let args = vec![program.to_string()];
+        let (command, pty) = command(&args, &launch(), &StdinMode::Null, false).unwrap();
+        let senders = Senders {
+            stdout: mpsc::channel(1).0,
+            stderr: None,
+        };
+        let (ch, mut cr) = broadcast::channel(1);
+        let supervision = supervision(Restart::Never);
+        match run_once(
+            command,
+            pty,
+            &capture(),
+            &senders,
+            &supervision,
+            &mut cr,
+            &ch,
+        )
+        .await
+        {
+            Ok(_) => panic!("\"{}\" was started", program),
+            Err(err) => err.code,
+        }
+    }
+
+    #[tokio::test]
+    async fn child_that_cant_start_exits_like_shells() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        assert_eq!(start_error(&missing.to_string_lossy()).await, 127);
+        // Not executable
+        let script = dir.path().join("script");
+        std::fs::write(&script, "exit 0\n").unwrap();
+        assert_eq!(start_error(&script.to_string_lossy()).await, 126);
+    }
+
+    #[tokio::test]
+    async fn terminated_child_gives_its_status() {
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let code = terminate(&mut child, Duration::from_secs(5)).await;
+        assert_eq!(code, 128 + libc::SIGTERM);
+
+        // Reaped before the shutdown, its status is still the one returned
+        let mut child = Command::new("sh").args(["-c", "exit 3"]).spawn().unwrap();
+        child.wait().await.unwrap();
+        assert_eq!(terminate(&mut child, Duration::from_secs(5)).await, 3);
+    }
+
+    // The restart marks in the output of a child
+    fn restarts(output: &[u8]) -> usize {
+        String::from_utf8_lossy(output)
+            .lines()
+            .filter(|line| line.starts_with("[logrotate] ") && line.contains("restarting"))
+            .count()
+    }
+
+    #[tokio::test]
+    async fn restart_policy_follows_the_exit_code() {
+        let cases = [
+            (Restart::Never, "exit 1", 0),
+            (Restart::OnFailure, "exit 0", 0),
+            (Restart::OnFailure, "exit 1", 1),
+            (Restart::Always, "exit 0", 1),
+        ];
+        for (restart, script, expected) in cases {
+            let supervision = Supervision {
+                restart_max: 1,
+                ..supervision(restart)
+            };
+            let (res, output) = run(script, launch(), capture(), supervision).await;
+            let code = script.trim_start_matches("exit ").parse::<i32>().unwrap();
+            assert_eq!(res.unwrap(), code, "{:?} {}", restart, script);
+            assert_eq!(restarts(&output), expected, "{:?} {}", restart, script);
+        }
+    }
+
+    #[tokio::test]
+    async fn child_stopped_by_the_shutdown_is_not_restarted() {
+        let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(16);
+        let senders = Senders {
+            stdout: sender,
+            stderr: None,
+        };
+        let (ch, _) = broadcast::channel(3);
+        let shutdown = ch.clone();
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(200)).await;
+            let _ = shutdown.send(());
+        });
+        let res = spawn(
+            shell_command(&["exec sleep 10".to_string()]),
+            launch(),
+            StdinMode::Null,
+            capture(),
+            senders,
+            supervision(Restart::Always),
+            ch,
+        )
+        .await;
+        assert_eq!(res.unwrap(), 128 + libc::SIGTERM);
+        let mut output = Vec::new();
+        while let Some(data) = receiver.recv().await {
+            output.extend_from_slice(&data);
+        }
+        assert_eq!(restarts(&output), 0);
+    }
+
+    #[tokio::test]
+    async fn restart_count_resets_after_the_window() {
+        // Fails twice, then succeeds; each run takes longer than the window
+        let script = "n=$(cat count 2>/dev/null || echo 0); echo $((n + 1)) > count; \
+                      sleep 0.1; [ $n -ge 2 ] && exit 0; exit 1";
+        // Without a window the restart allowed is used up by the first failure
+        let cases = [(Duration::ZERO, 1, 1), (Duration::from_millis(50), 2, 0)];
+        for (window, expected, code) in cases {
+            let dir = tempfile::tempdir().unwrap();
+            let launch = Launch {
+                chdir: Some(dir.path().to_string_lossy().into_owned()),
+                ..launch()
+            };
+            let supervision = Supervision {
+                restart_max: 1,
+                restart_window: window,
+                ..supervision(Restart::OnFailure)
+            };
+            let (res, output) = run(script, launch, capture(), supervision).await;
+            assert_eq!(res.unwrap(), code, "{:?}", window);
+            assert_eq!(restarts(&output), expected, "{:?}", window);
+        }
+    }
+
+    #[tokio::test]
+    async fn child_runs_in_the_working_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let launch = Launch {
+            chdir: Some(dir.path().to_string_lossy().into_owned()),
+            ..launch()
+        };
+        let script = "echo marked > ./marker";
+        let (res, _) = run(script, launch, capture(), supervision(Restart::Never)).await;
+        assert_eq!(res.unwrap(), 0);
+        assert_eq!(
+            std::fs::read(dir.path().join("marker")).unwrap(),
+            b"marked\n"
+        );
+        // Our own directory is left as it was
+        assert!(!std::path::Path::new("marker").exists());
+    }
+
+    #[tokio::test]
+    async fn output_written_before_the_shutdown_is_all_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let (sender, receiver) = mpsc::channel(16);
+        let senders = Senders {
+            stdout: sender,
+            stderr: None,
+        };
+        let (ch, _) = broadcast::channel(3);
+        // Interrupted once it printed everything
+        let shutdown = ch.clone();
+        let ready = dir.path().join("ready");
+        tokio::spawn(async move {
+            while !ready.exists() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+            let _ = shutdown.send(());
+        });
+        let script = "i=0; while [ $i -lt 10000 ]; do echo \"line $i\"; i=$((i + 1)); done; \
+                      touch ready; exec sleep 10";
+        let launch = Launch {
+            chdir: Some(dir.path().to_string_lossy().into_owned()),
+            ..launch()
+        };
+        let child = spawn(
+            shell_command(&[script.to_string()]),
+            launch,
+            StdinMode::Null,
+            capture(),
+            senders,
+            supervision(Restart::Never),
+            ch.clone(),
+        );
+        let rotate = crate::rotate::start(crate::rotate::tests::options(&path), receiver, ch);
+        let (res, _) = tokio::join!(child, rotate);
+        assert_eq!(res.unwrap(), 128 + libc::SIGTERM);
+        let written = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 10000);
+        assert!(lines
+            .iter()
+            .enumerate()
+            .all(|(i, line)| *line == format!("line {}", i)));
+    }
+
+    #[tokio::test]
+    async fn output_held_open_is_dropped_after_the_drain_timeout() {
+        let (sender, _receiver) = mpsc::channel(16);
+        let senders = Senders {
+            stdout: sender,
+            stderr: None,
+        };
+        let (ch, _) = broadcast::channel(3);
+        let shutdown = ch.clone();
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(200)).await;
+            let _ = shutdown.send(());
+        });
+        let supervision = Supervision {
+            drain_timeout: Some(Duration::from_millis(200)),
+            ..supervision(Restart::Never)
+        };
+        // The background sleep keeps stdout open after the shell is gone
+        let script = "sleep 10 & wait";
+        let started = Instant::now();
+        let res = spawn(
+            shell_command(&[script.to_string()]),
+            launch(),
+            StdinMode::Null,
+            capture(),
+            senders,
+            supervision,
+            ch,
+        )
+        .await;
+        assert_eq!(res.unwrap(), 128 + libc::SIGTERM);
+        assert!(started.elapsed() < OUTPUT_DRAIN_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn restart_mark_ends_with_the_delimiter() {
+        let capture = Capture {
+            delimiter: b'\0',
+            ..capture()
+        };
+        let supervision = Supervision {
+            restart_max: 1,
+            ..supervision(Restart::OnFailure)
+        };
+        let (res, output) = run("printf 'run\\0'; exit 1", launch(), capture, supervision).await;
+        assert_eq!(res.unwrap(), 1);
+        let records: Vec<&[u8]> = utils::Lines::new(&output, b'\0').collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0], b"run\0");
+        assert!(records[1].starts_with(b"[logrotate] ") && records[1].ends_with(b"(attempt 1)\0"));
+        assert_eq!(records[2], b"run\0");
     }
 }
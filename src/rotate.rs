@@ -1,51 +1,199 @@
-use chrono::{DateTime, Local};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use clap::ValueEnum;
 use fs::File;
-use libflate::gzip::Encoder;
+use regex::bytes::Regex;
+use std::borrow::Cow;
 use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 use std::fs;
+use std::future::pending;
 use std::io;
 use std::io::Error;
-use std::io::{copy, ErrorKind, Write};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::panic::{self, AssertUnwindSafe};
 use std::path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::select;
 use tokio::sync::broadcast;
 use tokio::sync::mpsc;
+use tokio::time;
 
+use crate::clock;
+use crate::compress;
+use crate::metrics;
+use crate::pm;
 use crate::utils;
+use crate::worker;
 
 #[derive(Clone, Debug, ValueEnum)]
 pub(crate) enum CutMode {
     Size,  // Represents the mode for cutting logs based on size
     Daily, // Represents the mode for cutting logs on a daily basis
+    None,  // Never cuts, the single file is compressed on shutdown if compression is on
+}
+
+// Where retention takes the age and order of rotated files from
+#[derive(Clone, Debug, ValueEnum)]
+pub(crate) enum RetentionBy {
+    Name,  // The day and sequence in the file name
+    Mtime, // The modification time, for names that no longer carry the right day
+}
+
+// Settings for a single rotated output
+#[derive(Clone, Debug)]
+pub(crate) struct Options {
+    pub path: Option<String>, // The path of the active log file, defaults to `logs/out`
+    pub cut_mode: CutMode,    // How the file is cut
+    pub file_size: Option<u64>, // The size limit in size mode
+    pub compress: Option<compress::Codec>, // The format rotated files are compressed with
+    pub compress_delay: usize, // The number of newest rotated files left uncompressed
+    pub compress_min_size: u64, // Rotated files smaller than this are left uncompressed
+    pub keep_uncompressed: bool, // Whether the plain file is kept next to its archive
+    pub daily_bundle: bool,   // Whether the files of past days are bundled into a tar.gz
+    pub encrypt: Option<String>, // The age recipient rotated files are encrypted to
+    pub checksum: bool,       // Whether a SHA-256 sidecar is written next to each archive
+    pub compress_on_exit: bool, // Whether the active file is rotated and compressed on shutdown
+    pub shred: bool,          // Whether expired files are overwritten before removal
+    pub archive_dir: Option<String>, // The directory rotated files are moved to, the active file's if not set
+    pub expire_to: Option<String>, // The trash directory expired files are moved to instead of removed
+    pub trash_keep_days: i64,      // The days files are kept in the trash, 0 keeps them forever
+    pub keep_days: i64,            // The number of days to keep rotated files, 0 keeps them forever
+    pub keep_num: usize,           // The number of newest rotated files to keep, 0 keeps all
+    pub max_total_size: u64,       // The total size of rotated files kept, 0 keeps any size
+    pub min_free_space: u64,       // The free space kept on the filesystem, 0 disables the check
+    pub retention_interval: Option<Duration>, // How often retention runs besides after rotations
+    pub retention_by: RetentionBy, // Where the age of rotated files is taken from
+    pub retention_loose: Option<glob::Pattern>, // Other files of the directory subject to retention
+    pub retention_exclude: Vec<glob::Pattern>, // The names of the files retention never removes
+    pub index_width: usize,        // The width the sequence of rotated names is zero-padded to
+    pub separator: String,         // What joins the path and the day in rotated names
+    pub filter: Filter,            // Which lines are written
+    pub timestamp: Option<String>, // The chrono format of the time prefixed to each line
+    pub strip_cr: bool,            // Whether lines ending in \r\n are written with a plain \n
+    pub heartbeat: Option<Duration>, // How often a status line is logged
+    pub tee: Option<i32>,          // The file descriptor each line is also written to
+    pub file_header: Option<String>, // The header written at the top of each new file
+    pub reopen_check: Option<Duration>, // How often the path is checked for external rotation
+    pub open_retries: u32, // How many times opening the file is retried before data is dropped
+    pub trim_partial: bool, // Whether a partial last line left in the file is removed on startup
+    pub shutdown_timeout: Option<Duration>, // How long the queued data is written after shutdown
+    pub max_age: Option<Duration>, // The age past which the file is rotated in size mode
+    pub sync: bool,        // Whether the active file is synced to disk before exiting
+    pub max_line_length: usize, // The length a partial line is split at, 0 never splits
+    pub delimiter: u8,     // The byte lines end with
+    pub on_rotate: Option<OnRotate>, // Told about each rotation once its file is processed
+}
+
+// What the rotation callback is told: the active file that was cut, the name it
+// ended up under and whether compressing it succeeded
+#[derive(Clone, Debug)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) struct RotationEvent {
+    pub old: String,
+    pub new: String,
+    pub compressed: bool,
+}
+
+// The callback run on the worker after each rotation, the binary sets none
+#[derive(Clone)]
+pub(crate) struct OnRotate(pub Arc<dyn Fn(&RotationEvent) + Send + Sync>);
+
+impl fmt::Debug for OnRotate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("OnRotate")
+    }
+}
+
+// Line filter applied before writing: a line is written when it matches `include`
+// (if set) and does not match `exclude` (if set)
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Filter {
+    pub include: Option<Regex>,
+    pub exclude: Option<Regex>,
+}
+
+impl Filter {
+    // Checks a complete line, the line ending (`delimiter`, or `\r\n`) is not part of the match
+    fn is_wanted(&self, line: &[u8], delimiter: u8) -> bool {
+        let line = utils::line_content(line, delimiter);
+        self.include.as_ref().is_none_or(|r| r.is_match(line))
+            && !self.exclude.as_ref().is_some_and(|r| r.is_match(line))
+    }
 }
 
 const DATE_FMT: &str = "%Y%m%d"; // Date format: Year-Month-Day
+const DATE_LEN: usize = 8; // The length of a day formatted with DATE_FMT
+const DEFAULT_BUNDLE_LEVEL: u32 = 6; // The gzip level of bundles when compression is off
+const DEFAULT_PATH: &str = "logs/out"; // The active file when no output is given
+const DEFAULT_FILE_SIZE: u64 = 1024 * 1024 * 20; // The size limit when none is given: 20MB
+
+// How long a temporary archive must be left untouched to be considered a leftover
+const STALE_TEMP_AGE: Duration = Duration::from_secs(60);
+
+// How long rotation is put off after the active file could not be archived
+const ROTATE_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+// The pause before the first retry of a failed open, doubled on each retry
+const OPEN_RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+// How long writes are dropped without retrying once opening the file keeps failing
+const OPEN_COOLDOWN: Duration = Duration::from_secs(5);
+
+// How often retention runs at most between rotations when the total size is capped
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+
+// How often the free space is checked while writing when a floor is set
+const SPACE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Whether destructive file operations are only reported, set once at startup
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::Relaxed);
+}
+
+// In dry-run mode, reports the action instead of it being performed, whatever the
+// debug switch, and returns true
+fn dry_run(action: fmt::Arguments) -> bool {
+    if !DRY_RUN.load(Ordering::Relaxed) {
+        return false;
+    }
+    eprintln!("dry run: would {}", action);
+    true
+}
+
+// Formats the day `time` falls on, as seen on the clock set at startup
+fn format_day(time: DateTime<Utc>) -> String {
+    clock::get().format(time, DATE_FMT)
+}
 
 // Returns the current day as a string in a specific format
 fn day() -> String {
-    Local::now().format(DATE_FMT).to_string()
+    format_day(Utc::now())
 }
 
 fn date_add(days: i64) -> String {
-    let dt: DateTime<Local> = Local::now();
-    let new_dt = dt + chrono::Duration::days(days);
-    new_dt.format(DATE_FMT).to_string()
+    format_day(Utc::now() + chrono::Duration::days(days))
 }
 
 // Checks if a file exists at the given path
 fn is_file(path: &String) -> bool {
-    return fs::metadata(path).is_ok_and(|meta| meta.is_file());
+    fs::metadata(path).is_ok_and(|meta| meta.is_file())
 }
 
-fn file_glob(file_path: &String) -> io::Result<Vec<String>> {
+fn file_glob(file_path: &str) -> io::Result<Vec<String>> {
     let p = path::Path::new(&file_path);
     let dir_path = p.parent();
     let base_path = p.file_name();
     if dir_path.is_none() {
-        return Err(Error::new(ErrorKind::Other, "invalid file path"));
+        return Err(Error::other("invalid file path"));
     }
     if base_path.is_none() {
-        return Err(Error::new(ErrorKind::Other, "invalid file name"));
+        return Err(Error::other("invalid file name"));
     }
     let dir = dir_path.unwrap();
     if !dir.exists() {
@@ -53,7 +201,7 @@ fn file_glob(file_path: &String) -> io::Result<Vec<String>> {
     }
     let bp = base_path.unwrap().to_str();
     if bp.is_none() {
-        return Err(Error::new(ErrorKind::Other, "invalid base directory"));
+        return Err(Error::other("invalid base directory"));
     }
     let base_path: String = bp.unwrap().to_string();
     let mut files = vec![];
@@ -64,20 +212,137 @@ fn file_glob(file_path: &String) -> io::Result<Vec<String>> {
                 if let Some(s) = dir.join(&file_name).as_os_str().to_str() {
                     files.push(s.to_string());
                 } else {
-                    return Err(Error::new(
-                        ErrorKind::Other,
-                        format!("invalid file name: {}", &file_name),
-                    ));
+                    return Err(Error::other(format!("invalid file name: {}", &file_name)));
                 }
             }
         } else {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("invalid file name: {:?}", de.file_name()),
-            ));
+            return Err(Error::other(format!(
+                "invalid file name: {:?}",
+                de.file_name()
+            )));
+        }
+    }
+    Ok(files)
+}
+
+// The names a rotated file takes once compressed and/or encrypted, for every
+// format, since earlier runs may have used another setting
+fn archive_variants(filename: &str) -> Vec<String> {
+    let mut names: Vec<String> = compress::EXTENSIONS
+        .iter()
+        .map(|ext| format!("{}.{}", filename, ext))
+        .collect();
+    names.push(filename.to_string());
+    let encrypted: Vec<String> = names
+        .iter()
+        .map(|name| format!("{}.{}", name, compress::ENCRYPTED_EXTENSION))
+        .collect();
+    names.pop();
+    names.extend(encrypted);
+    names
+}
+
+// Checks if a rotated file exists either plain, compressed or encrypted, regardless
+// of the current settings
+fn archive_exists(filename: &String) -> bool {
+    is_file(filename) || archive_variants(filename).iter().any(is_file)
+}
+
+// Strips the encryption and compression extensions of a rotated file, if any
+fn strip_compressed(filename: &str) -> &str {
+    let filename = filename
+        .strip_suffix(compress::ENCRYPTED_EXTENSION)
+        .and_then(|f| f.strip_suffix('.'))
+        .unwrap_or(filename);
+    compress::EXTENSIONS
+        .iter()
+        .find_map(|ext| filename.strip_suffix(ext)?.strip_suffix('.'))
+        .unwrap_or(filename)
+}
+
+// Checks whether the name carries a compression extension, under the encryption one
+fn is_compressed(filename: &str) -> bool {
+    let filename = filename
+        .strip_suffix(compress::ENCRYPTED_EXTENSION)
+        .and_then(|f| f.strip_suffix('.'))
+        .unwrap_or(filename);
+    compress::EXTENSIONS
+        .iter()
+        .any(|ext| filename.strip_suffix(ext).is_some_and(|f| f.ends_with('.')))
+}
+
+// Scans the directory for the files rotated on `day` and returns the sequence
+// number following the highest one found, both plain and compressed files are counted
+fn scan_next_seq(prefix: &str, day: &str) -> u32 {
+    let prefix = format!("{}{}-", prefix, day);
+    match file_glob(&prefix) {
+        Ok(files) => {
+            files
+                .iter()
+                .filter_map(|file| {
+                    let seq = &file[prefix.len()..];
+                    strip_compressed(seq).parse::<u32>().ok()
+                })
+                .max()
+                .unwrap_or(0)
+                + 1
+        }
+        Err(err) => {
+            error!("failed to list log files: {:+?}", err);
+            1
+        }
+    }
+}
+
+// Names the `seq`th file rotated on `day`, with the sequence zero-padded to `width`
+// digits. Unpadded, `-10` sorts before `-2` by name.
+fn seq_filename(prefix: &str, day: &str, seq: u32, width: usize) -> String {
+    format!("{}{}-{:0width$}", prefix, day, seq)
+}
+
+// The start shared by the names of the files rotated from `path`, in the archive
+// directory if one is set. A relative archive directory is taken from the
+// directory of `path`.
+fn rotated_prefix(path: &str, options: &Options) -> String {
+    let Some(archive_dir) = &options.archive_dir else {
+        return format!("{}{}", path, options.separator);
+    };
+    let file = path::Path::new(path);
+    let dir = file
+        .parent()
+        .unwrap_or(path::Path::new(""))
+        .join(archive_dir);
+    let name = file.file_name().unwrap_or_default();
+    format!("{}{}", dir.join(name).to_string_lossy(), options.separator)
+}
+
+// Removes the partial line a crash or power loss may have left at the end of the
+// file, so that appending doesn't glue the next line onto it. Returns the number
+// of bytes removed.
+fn trim_partial_line(path: &str, delimiter: u8) -> io::Result<u64> {
+    let mut file = match fs::OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let len = file.metadata()?.len();
+    let mut end = len; // The end of the part not searched yet
+    let mut buf = vec![0u8; 8192];
+    while end > 0 {
+        let start = end.saturating_sub(buf.len() as u64);
+        let chunk = &mut buf[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+        if let Some(index) = chunk.iter().rposition(|&b| b == delimiter) {
+            end = start + index as u64 + 1;
+            break;
         }
+        end = start;
+    }
+    if end < len && !dry_run(format_args!("truncate \"{}\" to {} bytes", path, end)) {
+        file.set_len(end)?;
     }
-    return Ok(files);
+    Ok(len - end)
 }
 
 // Opens a file at the given path and returns a tuple containing the file handle and its metadata
@@ -92,379 +357,3317 @@ fn open_file(path: &str) -> io::Result<(File, Option<fs::Metadata>)> {
         Err(err) => match err.kind() {
             ErrorKind::NotFound => File::create(path).map(|fp| (fp, None)),
             _ => {
-                log!("failed to read file \"{}\" metadata: {:+?}", path, err);
+                error!("failed to read file \"{}\" metadata: {:+?}", path, err);
                 Err(err)
             }
         },
     }
 }
 
-// Flushes the contents of the file handle to disk
-// Prints an error message if there is an error flushing the file
-fn file_flush(file: &Option<File>) {
-    if let Some(mut fp) = file.as_ref() {
-        if let Err(err) = fp.flush() {
-            log!("failed to flush file: {:+?}", err)
+// Converts the header option to the bytes written, with a trailing newline
+fn header_bytes(header: &Option<String>) -> Vec<u8> {
+    match header {
+        Some(h) if !h.is_empty() => {
+            let mut bytes = h.as_bytes().to_vec();
+            if !h.ends_with('\n') {
+                bytes.push(b'\n');
+            }
+            bytes
         }
+        _ => Vec::new(),
     }
 }
 
-fn gzip_encode(filename: &String) -> io::Result<()> {
-    let mut inf = File::open(filename)?;
-    let out = File::create(format!("{}.gz", filename))?;
-    let mut encoder = Encoder::new(out)?;
-    copy(&mut inf, &mut encoder)?;
-    drop(inf);
-    drop(encoder.finish().into_result()?);
-    fs::remove_file(filename)?;
-    Ok(())
-}
-
-fn remove_log_files(file_path: &String, day: &String) {
-    let file_path = format!("{}.{}", file_path, day);
-    match file_glob(&file_path) {
-        Ok(files) => {
-            for file in files {
-                match fs::remove_file(&file) {
-                    Ok(_) => {
-                        log!("removed file \"{}\"", &file);
-                    }
-                    Err(err) => {
-                        log!("failed to remove file \"{}\": {:+?}", &file, err);
-                    }
-                }
-            }
-        }
-        Err(err) => {
-            log!("failed to list log files: {:+?}", err);
-        }
+// Writes the header to a file that was just created or is still empty, existing
+// content is never prefixed again. Returns the number of bytes written.
+fn write_header(fp: &mut File, meta: &Option<fs::Metadata>, header: &[u8]) -> io::Result<u64> {
+    if header.is_empty() || meta.as_ref().is_some_and(|m| m.len() > 0) {
+        return Ok(0);
     }
+    fp.write_all(header)?;
+    Ok(header.len() as u64)
 }
 
-pub trait Rotate {
-    // Rotates the filename by appending the current day to it
-    // If the rotated filename already exists, it appends a unique identifier to it
-    fn rotate_filename(&self, path: &String, compress: bool, mul: bool) -> String {
-        let day = day();
-        if !mul {
-            // If no multi mode, check if the file exists
-            let filename = path.clone() + "." + day.as_str();
-            if (compress && !is_file(&format!("{}.gz", filename)))
-                || (!compress && !is_file(&filename))
-            {
-                return filename;
-            }
-        }
-
-        let mut i = 1;
-        loop {
-            let filename = format!("{:}.{:}-{:}", path, day, i);
-            if (compress && !is_file(&format!("{}.gz", filename)))
-                || (!compress && !is_file(&filename))
-            {
-                return filename;
-            }
-            i += 1;
-        }
+// Checks whether the file at `path` is no longer the one behind the open handle,
+// which happens when another process deletes or renames the active file.
+// Device and inode are compared on Unix, other platforms fall back to checking
+// that the path exists and is not smaller than the open file.
+fn is_replaced(path: &str, file: &Option<File>) -> bool {
+    let fp = match file {
+        Some(fp) => fp,
+        None => return false,
+    };
+    let path_meta = match fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(err) => return err.kind() == ErrorKind::NotFound,
+    };
+    let file_meta = match fp.metadata() {
+        Ok(meta) => meta,
+        Err(_) => return false,
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        path_meta.dev() != file_meta.dev() || path_meta.ino() != file_meta.ino()
+    }
+    #[cfg(not(unix))]
+    {
+        path_meta.len() < file_meta.len()
     }
-
-    fn receiver(&mut self) -> &mut mpsc::Receiver<Vec<u8>>;
-    fn get_file(&mut self, len: u64) -> io::Result<&mut File>;
-    fn flush(&mut self);
-    fn close(&mut self);
 }
 
+// How often the active path is checked for being deleted or moved by another
+// process, `None` when the check is disabled
 #[derive(Debug)]
-struct SizeRotate {
-    path: String,                      // The path where the file will be written
-    receiver: mpsc::Receiver<Vec<u8>>, // The receiver for receiving byte vectors
-    size_limit: u64,                   // The maximum size limit for the file
-    cur_size: u64,                     // The current size of the file
-    file: RefCell<Option<File>>,       // The file being written (wrapped in a RefCell)
-    compress: bool,                    // Whether to compress the file
-    keep_days: i64,                    // The number of days to keep the log files
+pub(crate) struct ReopenCheck {
+    interval: Option<Duration>,
+    checked_at: Instant, // The last time the path was checked
 }
 
-impl SizeRotate {
-    fn new(
-        path: String,
-        receiver: mpsc::Receiver<Vec<u8>>,
-        file_size: Option<u64>,
-        compress: bool,
-        keep_days: i64,
-    ) -> Self {
-        let slo = file_size.or_else(|| Some(1024 * 1024 * 20)); // If file_size is None, set it to 20MB (default)
-
+impl ReopenCheck {
+    fn new(interval: Option<Duration>) -> Self {
         Self {
-            path,
-            receiver,
-            size_limit: slo.unwrap(),
-            cur_size: 0,
-            file: RefCell::default(),
-            compress,
-            keep_days,
+            interval,
+            checked_at: Instant::now(),
         }
     }
 }
 
-impl Rotate for SizeRotate {
-    fn receiver(&mut self) -> &mut mpsc::Receiver<Vec<u8>> {
-        &mut self.receiver
+// The rotation state of an output, saved next to the active file as `.<name>.state`
+// so that a restart carries on with the same day and sequence numbers instead of
+// deriving them from mtimes and the rotated files left in the directory
+#[derive(Debug, Default)]
+struct State {
+    day: String, // The day the active file was created on, or the day of `seq`
+    size: u64,   // The size of the active file when the state was saved
+    seq: u32,    // The next sequence number of the files rotated on `day`
+    born: u64,   // When the active file was created, in seconds since the epoch, 0 if unknown
+}
+
+impl State {
+    fn path(path: &str) -> String {
+        let temp = compress::temp_path(path);
+        format!("{}.state", temp.strip_suffix(".tmp").unwrap_or(&temp))
     }
 
-    // Get a mutable reference to a file for writing data.
-    // If the file is not already open, it opens it and checks the size limit.
-    // If the size limit is exceeded, it performs file rotation by flushing the file,
-    // renaming it, and recursively calling `get_file` to get a new file.
-    fn get_file(&mut self, len: u64) -> io::Result<&mut File> {
-        if self.file.get_mut().is_none() {
-            let (fp, exists) = open_file(self.path.as_str())?;
-            self.file.replace(Some(fp));
-            if let Some(meta) = exists {
-                self.cur_size = meta.len();
-            } else {
-                self.cur_size = 0;
+    // Loads the saved state, `None` if there is none or it can't be read
+    fn load(path: &str) -> Option<State> {
+        let content = fs::read_to_string(State::path(path)).ok()?;
+        let mut state = State::default();
+        for line in content.lines() {
+            match line.split_once('=')? {
+                ("day", day) => state.day = day.to_string(),
+                ("size", size) => state.size = size.parse().ok()?,
+                ("seq", seq) => state.seq = seq.parse().ok()?,
+                ("born", born) => state.born = born.parse().ok()?,
+                _ => {}
             }
         }
-        if self.cur_size + len <= self.size_limit {
-            // Check if the current size plus the new length is less than or equal to the size limit
-            self.cur_size += len;
-            return Ok(self.file.get_mut().as_mut().unwrap());
+        let valid = state.day.len() == DATE_LEN && state.day.bytes().all(|b| b.is_ascii_digit());
+        valid.then_some(state)
+    }
+
+    fn save(&self, path: &str) {
+        let content = format!(
+            "day={}\nsize={}\nseq={}\nborn={}\n",
+            self.day, self.size, self.seq, self.born
+        );
+        if let Err(err) = fs::write(State::path(path), content) {
+            error!("failed to save the state of \"{}\": {:+?}", path, err);
         }
+    }
+}
 
-        let mut fp = self.file.take().unwrap();
+// Flushes the contents of the file handle to disk
+// Prints an error message if there is an error flushing the file
+fn file_flush(file: &Option<File>) {
+    if let Some(mut fp) = file.as_ref() {
         if let Err(err) = fp.flush() {
-            log!("failed to flush the file: {:+?}", err);
+            error!("failed to flush file: {:+?}", err)
         }
+    }
+}
 
-        drop(fp);
+// What became of the active file when it was archived
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Archived {
+    Moved,  // It was moved to its rotated name
+    Failed, // It is still in place, rotation is retried later
+    DryRun, // It was left in place by --dry-run
+}
 
-        let new_filename = self.rotate_filename(&self.path, self.compress, true);
-        log!("move file: {:?} -> {:?}", self.path, new_filename);
-        if let Err(err) = fs::rename(self.path.clone(), &new_filename) {
-            log!("failed to move the file: {:+?}", err)
-        } else {
-            if self.compress {
-                gzip_encode(&new_filename)?;
-            }
+// Moves the active file to its rotated name, returns what became of it.
+// When the rename fails (e.g. EXDEV, the archive directory being on another
+// filesystem) the file is copied and truncated instead, which is safe as it is
+// closed and we are its only writer.
+fn archive_file(path: &String, new_filename: &String) -> Archived {
+    if dry_run(format_args!("move \"{}\" to \"{}\"", path, new_filename)) {
+        return Archived::DryRun;
+    }
+    info!("move file: {:?} -> {:?}", path, new_filename);
+    if !create_archive_dir(new_filename) {
+        return Archived::Failed;
+    }
+    if let Err(err) = fs::rename(path, new_filename) {
+        warn!("failed to move the file, copying it instead: {:+?}", err);
+        let res = fs::copy(path, new_filename)
+            .and_then(|_| File::options().write(true).open(path))
+            .and_then(|fp| fp.set_len(0));
+        if let Err(err) = res {
+            error!("failed to copy the file: {:+?}", err);
+            let _ = fs::remove_file(new_filename);
+            return Archived::Failed;
         }
-
-        // drop the expired file
-        let expire_day = date_add(-self.keep_days);
-        remove_log_files(&self.path, &expire_day);
-
-        self.get_file(len)
     }
+    metrics::inc(&metrics::ROTATIONS);
+    Archived::Moved
+}
 
-    // Flushes the file to disk.
-    #[inline]
-    fn flush(&mut self) {
-        let fp = self.file.borrow_mut();
-        file_flush(&fp);
+// Writes back the start of the file a crash interrupted the compression of into
+// `tmp`, punched out already: the rotated file the archive is named after, or the
+// active file `path` when it was compressed as it was cut. Returns whether one was.
+fn restore_consumed(tmp: &str, path: &str) -> io::Result<bool> {
+    let tmp_path = path::Path::new(tmp);
+    let target = tmp_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix('.')?.strip_suffix(".tmp"));
+    let found = target.and_then(|target| {
+        compress::Format::value_variants()
+            .iter()
+            .find_map(|format| {
+                let rotated = target.strip_suffix(format.extension())?.strip_suffix('.')?;
+                Some((*format, rotated))
+            })
+    });
+    let Some((format, rotated)) = found.filter(|_| !DRY_RUN.load(Ordering::Relaxed)) else {
+        return Ok(false);
+    };
+    let rotated = tmp_path.with_file_name(rotated);
+    let sources = [rotated.to_string_lossy().into_owned(), path.to_string()];
+    for source in sources.iter().filter(|f| is_file(f)) {
+        let len = compress::restore_consumed(source, tmp, format)?;
+        if len > 0 {
+            warn!(
+                "restored {} bytes of \"{}\" punched out by an interrupted compression",
+                len, source
+            );
+            return Ok(true);
+        }
     }
+    Ok(false)
+}
 
-    // Closes the file by flushing it and dropping the file handle.
-    #[inline]
-    fn close(&mut self) {
-        self.flush();
-        drop(self.file.take());
+// Cuts the active file of a rotator to its rotated name. With compression right
+// after rotation, the file is streamed from its path straight into the archive,
+// which becomes the rotated file, and the file is removed: it is read once and
+// never renamed or copied, whichever filesystem the archive directory is on, and
+// punched out as it is read, so it takes little more space than it did. The
+// write loop waits for the archive meanwhile. Otherwise, or if that fails, it is
+// moved with `archive_file` and compressed by the worker afterwards. Returns what
+// became of the file and the rotated name.
+fn cut_file(path: &String, new_filename: String, archive: &Archive) -> (Archived, String) {
+    let codec = archive.compress.filter(|_| {
+        archive.compress_delay == 0
+            && !archive.keep_uncompressed
+            && !is_too_small(path, archive.compress_min_size)
+            && !DRY_RUN.load(Ordering::Relaxed)
+    });
+    if let Some(codec) = codec.filter(|_| create_archive_dir(&new_filename)) {
+        let target = format!("{}.{}", new_filename, codec.format.extension());
+        info!("compress file: {:?} -> {:?}", path, target);
+        match compress::compress_to(path, &target, codec, true) {
+            Ok(_) => {
+                // Emptied already, the next rotation finds nothing to archive again
+                if let Err(err) = fs::remove_file(path) {
+                    warn!("failed to remove \"{}\", left empty: {:+?}", path, err);
+                }
+                metrics::inc(&metrics::ROTATIONS);
+                return (Archived::Moved, target);
+            }
+            Err(err) => warn!(
+                "failed to compress \"{}\" on rotation, moving it instead: {:+?}",
+                path, err
+            ),
+        }
     }
+    let archived = archive_file(path, &new_filename);
+    (archived, new_filename)
 }
 
-unsafe impl Send for SizeRotate {}
+// Creates the directory of `file` when it is missing, as the archive directory
+// may be another one than the active file's. Returns whether it exists.
+fn create_archive_dir(file: &str) -> bool {
+    let dir = match path::Path::new(file).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() && !dir.exists() => dir,
+        _ => return true,
+    };
+    if dry_run(format_args!("create directory {:?}", dir)) {
+        return false;
+    }
+    match fs::create_dir_all(dir) {
+        Ok(_) => true,
+        Err(err) => {
+            error!("failed to create directory {:?}: {:+?}", dir, err);
+            false
+        }
+    }
+}
 
-#[derive(Debug)]
-struct DailyRotate {
-    path: String,                      // The path where the rotated files will be stored
-    receiver: mpsc::Receiver<Vec<u8>>, // The receiver end of a channel that receives byte vectors
-    file: RefCell<Option<File>>,       // A mutable reference to an optional file
-    compress: bool,                    // Whether to compress the rotated files
-    keep_days: i64,                    // The number of days to keep rotated files
-    create_day: String,                // The day when the file was created
+// Puts rotation on hold after the active file could not be archived, so that the
+// rotators keep appending to it instead of retrying on every write
+fn hold_rotation(path: &str) -> Option<Instant> {
+    error!(
+        "failed to rotate \"{}\", appending to it for {:?}",
+        path, ROTATE_RETRY_INTERVAL
+    );
+    Some(Instant::now() + ROTATE_RETRY_INTERVAL)
 }
 
-impl DailyRotate {
-    // Constructs a new instance of DailyRotate
-    fn new(
-        path: String,
-        receiver: mpsc::Receiver<Vec<u8>>,
-        compress: bool,
-        keep_days: i64,
-    ) -> Self {
-        Self {
-            path,
-            receiver,
-            file: RefCell::default(),
-            compress,
-            keep_days,
-            create_day: String::new(),
+// Puts rotation on hold when the active file could not be archived or was left in
+// place by a dry run, the latter for good. Returns the rotated name if it was moved.
+fn rotated_or_held(
+    archived: Archived,
+    path: &str,
+    new_filename: String,
+    retry_at: &mut Option<Instant>,
+) -> Option<String> {
+    match archived {
+        Archived::Moved => return Some(new_filename),
+        Archived::Failed => *retry_at = hold_rotation(path),
+        // The file is never moved during a dry run, so there is nothing to retry
+        Archived::DryRun => {
+            info!("dry run: appending to \"{}\" instead of rotating it", path);
+            *retry_at = Instant::now().checked_add(Duration::from_secs(u32::MAX.into()));
         }
     }
+    None
 }
 
-impl Rotate for DailyRotate {
-    // Receives data from the receiver channel
-    // Returns the received data or an error
-    fn receiver(&mut self) -> &mut mpsc::Receiver<Vec<u8>> {
-        &mut self.receiver
-    }
+fn is_held(retry_at: &Option<Instant>) -> bool {
+    retry_at.is_some_and(|at| Instant::now() < at)
+}
 
-    // Gets the file to write data to
-    // If the file is not open, it opens the file and sets the create_day field
-    // If the current day is different from the create_day, it rotates the file by flushing, renaming, and opening a new file
-    // Returns a mutable reference to the file
-    fn get_file(&mut self, len: u64) -> io::Result<&mut File> {
-        let day = day(); // Get the current day
-        if self.file.get_mut().is_none() {
-            let (fp, exists) = open_file(self.path.as_str())?; // Open the file
-            self.file.replace(Some(fp)); // Replace the file with the opened file
-            if let Some(meta) = exists {
-                let date_time: DateTime<Local> = DateTime::from(meta.modified()?);
-                self.create_day = date_time.format(DATE_FMT).to_string(); // Set the create_day field based on the file creation time
-            } else {
-                self.create_day = day.clone(); // Set the create_day field to the current day
-            }
-        }
+// The rotated files being compressed, which retention must leave alone
+static COMPRESSING: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
 
-        if self.create_day == day {
-            return Ok(self.file.get_mut().as_mut().unwrap()); // Return a mutable reference to the file
-        }
+fn is_compressing(filename: &String) -> bool {
+    COMPRESSING.lock().is_ok_and(|set| set.contains(filename))
+}
 
-        let mut fp = self.file.take().unwrap();
-        if let Err(err) = fp.flush() {
-            log!("failed to flush the file: {:+?}", err);
-        }
+// What happens to rotated files: how they are compressed and when they expire
+#[derive(Clone, Debug)]
+struct Archive {
+    compress: Option<compress::Codec>, // The format rotated files are compressed with
+    compress_delay: usize,             // The number of newest rotated files left uncompressed
+    compress_min_size: u64,            // Rotated files smaller than this are left uncompressed
+    keep_uncompressed: bool,           // Whether the plain file is kept next to its archive
+    daily_bundle: bool,                // Whether the files of past days are bundled into a tar.gz
+    encrypt: Option<String>,           // The age recipient rotated files are encrypted to
+    checksum: bool,                    // Whether a SHA-256 sidecar is written next to each archive
+    shred: bool,                       // Whether expired files are overwritten before removal
+    compress_on_exit: bool, // Whether the active file is rotated and compressed on shutdown
+    expire_to: Option<String>, // The trash directory expired files are moved to
+    trash_keep_days: i64,   // The days files are kept in the trash
+    keep_days: i64,         // The number of days to keep rotated files
+    keep_num: usize,        // The number of newest rotated files to keep
+    max_total_size: u64,    // The total size of rotated files kept
+    min_free_space: u64,    // The free space kept on the filesystem
+    retention_by: RetentionBy, // Where the age of rotated files is taken from
+    retention_loose: Option<glob::Pattern>, // Other files of the directory subject to retention
+    retention_exclude: Vec<glob::Pattern>, // The names of the files retention never removes
+    path: String,           // The active file, as told to `on_rotate`
+    on_rotate: Option<OnRotate>, // Told about each rotation once its file is processed
+}
 
-        drop(fp);
+impl Archive {
+    fn new(options: &Options) -> Self {
+        Self {
+            compress: options.compress,
+            compress_delay: options.compress_delay,
+            compress_min_size: options.compress_min_size,
+            keep_uncompressed: options.keep_uncompressed,
+            daily_bundle: options.daily_bundle,
+            encrypt: options.encrypt.clone(),
+            checksum: options.checksum,
+            shred: options.shred,
+            compress_on_exit: options.compress_on_exit,
+            expire_to: options.expire_to.clone(),
+            trash_keep_days: options.trash_keep_days,
+            keep_days: options.keep_days,
+            keep_num: options.keep_num,
+            max_total_size: options.max_total_size,
+            min_free_space: options.min_free_space,
+            retention_by: options.retention_by.clone(),
+            retention_loose: options.retention_loose.clone(),
+            retention_exclude: options.retention_exclude.clone(),
+            path: options.path.as_deref().unwrap_or(DEFAULT_PATH).to_string(),
+            on_rotate: options.on_rotate.clone(),
+        }
+    }
+}
 
-        let new_filename = self.rotate_filename(&self.path, self.compress, false);
-        log!("move file: {:?} -> {:?}", self.path, new_filename);
-        if let Err(err) = fs::rename(self.path.clone(), &new_filename) {
-            log!("failed to move the file: {:+?}", err);
-        } else {
-            if self.compress {
-                gzip_encode(&new_filename)?;
+// Removes the temporary archives left over by a crash during compression, once the
+// file they were compressing is restored from them. A file still being written (by
+// another process compressing the same output) is modified continuously, so only
+// files untouched for a while are removed.
+fn remove_stale_temp_files(prefix: &str, path: &str) {
+    let prefix = compress::temp_path(prefix);
+    let prefix = prefix.strip_suffix(".tmp").unwrap_or(&prefix).to_string();
+    let files = match file_glob(&prefix) {
+        Ok(files) => files,
+        Err(err) => {
+            error!("failed to list log files: {:+?}", err);
+            return;
+        }
+    };
+    for file in files.iter().filter(|f| f.ends_with(".tmp")) {
+        match restore_consumed(file, path) {
+            Ok(false) => {}
+            Ok(true) => {
+                let _ = fs::remove_file(file);
+                continue;
+            }
+            Err(err) => {
+                error!(
+                    "failed to restore from \"{}\", keeping it: {:+?}",
+                    file, err
+                );
+                continue;
             }
         }
-        // drop the expired file
-        let expire_day = date_add(-self.keep_days);
-        remove_log_files(&self.path, &expire_day);
-        self.get_file(len)
+        let stale = fs::metadata(file)
+            .and_then(|meta| meta.modified())
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > STALE_TEMP_AGE));
+        if !stale || dry_run(format_args!("remove stale temporary file \"{}\"", file)) {
+            continue;
+        }
+        match fs::remove_file(file) {
+            Ok(_) => info!("removed stale temporary file \"{}\"", file),
+            Err(err) => error!("failed to remove file \"{}\": {:+?}", file, err),
+        }
     }
+}
 
-    // Flushes the current file
-    #[inline]
-    fn flush(&mut self) {
-        let fp = self.file.borrow_mut();
-        file_flush(&fp);
-    }
+// Checks if a rotated file is too small for compression to be worth it
+fn is_too_small(filename: &String, min_size: u64) -> bool {
+    min_size > 0 && fs::metadata(filename).is_ok_and(|meta| meta.len() < min_size)
+}
 
-    // Closes the file by flushing it and dropping the file handle
-    #[inline]
-    fn close(&mut self) {
-        self.flush();
-        drop(self.file.take());
+// Compresses a rotated file, keeping it uncompressed if that fails or if it is
+// too small for compression to be worth it. Returns the name of the resulting file.
+fn compress_rotated(filename: &String, codec: compress::Codec, archive: &Archive) -> String {
+    if is_too_small(filename, archive.compress_min_size) {
+        info!(
+            "skipped compressing \"{}\", smaller than {} bytes",
+            filename, archive.compress_min_size
+        );
+        return filename.clone();
     }
+    if let Err(err) = compress::compress_file(filename, codec, archive.keep_uncompressed) {
+        metrics::inc(&metrics::COMPRESS_FAILURES);
+        error!(
+            "failed to compress file \"{}\", keeping it uncompressed: {:+?}",
+            filename, err
+        );
+        return filename.clone();
+    }
+    format!("{}.{}", filename, codec.format.extension())
 }
 
-unsafe impl Send for DailyRotate {}
-
-pub fn new(
-    file_path: Option<String>,
-    mode: CutMode,
-    file_size: Option<u64>,
-    compress: bool,
-    keep_days: i64,
-    receiver: mpsc::Receiver<Vec<u8>>,
-) -> Box<dyn Rotate + Send> {
-    let log_path = match file_path {
-        Some(s) => s,
-        None => String::from("logs/out"),
+// Compresses then encrypts a rotated file, as configured. Returns the name of the
+// resulting file.
+fn process_rotated(filename: &String, archive: &Archive) -> String {
+    if dry_run(format_args!("compress or encrypt \"{}\"", filename)) {
+        return filename.clone();
+    }
+    let mut result = match archive.compress {
+        // Compressed already when it was cut, see `cut_file`
+        Some(_) if strip_compressed(filename) != filename => filename.clone(),
+        Some(codec) => compress_rotated(filename, codec, archive),
+        None => filename.clone(),
     };
-    if let Some(log_dir) = path::Path::new(&log_path).parent() {
-        if !log_dir.exists() {
-            if let Err(err) = fs::create_dir_all(log_dir) {
-                panic!("failed to create log directory: {:+?}", err)
+    if let Some(recipient) = &archive.encrypt {
+        match compress::encrypt_file(&result, recipient) {
+            Ok(encrypted) => result = encrypted,
+            Err(err) => {
+                metrics::inc(&metrics::COMPRESS_FAILURES);
+                error!(
+                    "failed to encrypt file \"{}\", keeping it unencrypted: {:+?}",
+                    result, err
+                );
             }
         }
     }
-    match mode {
-        CutMode::Size => {
-            let r = SizeRotate::new(log_path, receiver, file_size, compress, keep_days);
-            Box::new(r)
-        }
-        CutMode::Daily => {
-            let r = DailyRotate::new(log_path, receiver, compress, keep_days);
-            Box::new(r)
-        }
+    if !is_file(filename) {
+        // A sidecar written for the file by an earlier run with other settings
+        let _ = fs::remove_file(format!("{}.{}", filename, compress::CHECKSUM_EXTENSION));
     }
+    result
 }
 
-fn write_all(rotate: &mut Box<dyn Rotate + Send>, data: &[u8]) {
-    match rotate.get_file(data.len() as u64) {
-        Ok(fp) => {
-            if let Err(err) = fp.write_all(data) {
-                log!("failed to write content to file: {:+?}", err);
-            }
-        }
+// Writes the checksum sidecar of a final archive, if enabled
+fn checksum_archive(filename: &str, archive: &Archive) {
+    if !archive.checksum || dry_run(format_args!("write the checksum of \"{}\"", filename)) {
+        return;
+    }
+    if let Err(err) = compress::write_checksum(filename) {
+        error!("failed to write checksum of \"{}\": {:+?}", filename, err);
+    }
+}
+
+// Writes the missing checksum sidecars of the archives already on disk, run once at
+// startup when checksums are enabled. Plain rotated files only count as archives
+// when neither compression nor encryption is on, otherwise they are still to be
+// processed and get their sidecar then.
+fn backfill_checksums(prefix: &str, archive: &Archive) {
+    let processed = archive.compress.is_some() || archive.encrypt.is_some();
+    let files = match file_glob(prefix) {
+        Ok(files) => files,
         Err(err) => {
-            log!("failed to open file: {:+?}", err);
+            error!("failed to list log files: {:+?}", err);
+            return;
+        }
+    };
+    for file in files {
+        if !is_rotated(prefix, &file)
+            || is_sidecar(&file)
+            || (processed && strip_compressed(&file) == file)
+        {
+            continue;
+        }
+        if !is_file(&format!("{}.{}", file, compress::CHECKSUM_EXTENSION)) {
+            checksum_archive(&file, archive);
         }
     }
 }
 
-pub async fn start(
-    file_path: Option<String>,
-    cut_mode: CutMode,
-    file_size: Option<u64>,
-    compress: bool,
-    keep_days: i64,
-    receiver: mpsc::Receiver<Vec<u8>>,
-    ch: broadcast::Sender<()>,
-) {
-    let mut rotate = new(
-        file_path, cut_mode, file_size, compress, keep_days, receiver,
+fn is_sidecar(filename: &str) -> bool {
+    filename
+        .strip_suffix(compress::CHECKSUM_EXTENSION)
+        .is_some_and(|f| f.ends_with('.'))
+}
+
+// Compresses every rotated file of the output that has no compressed form yet, for
+// the `compress` subcommand. Prints a summary and returns whether all of them
+// could be compressed.
+pub fn compress_all(options: &Options, codec: compress::Codec) -> bool {
+    let path = options.path.as_deref().unwrap_or(DEFAULT_PATH);
+    let (mut count, mut saved, mut failed) = (0, 0i64, 0);
+    for file in list_uncompressed(&rotated_prefix(path, options)) {
+        if is_too_small(&file, options.compress_min_size) {
+            continue;
+        }
+        if dry_run(format_args!("compress \"{}\"", file)) {
+            continue;
+        }
+        let before = fs::metadata(&file).map(|meta| meta.len()).unwrap_or(0);
+        match compress::compress_file(&file, codec, options.keep_uncompressed) {
+            Ok(_) => {
+                let target = format!("{}.{}", file, codec.format.extension());
+                let after = fs::metadata(&target).map(|meta| meta.len()).unwrap_or(0);
+                count += 1;
+                saved += before as i64 - after as i64;
+                println!("compressed \"{}\" ({} -> {} bytes)", file, before, after);
+            }
+            Err(err) => {
+                failed += 1;
+                eprintln!("failed to compress \"{}\": {}", file, err);
+            }
+        }
+    }
+    println!(
+        "\"{}\": compressed {} files, saved {} bytes, {} failed",
+        path, count, saved, failed
     );
-    let mut tail = None;
-    loop {
-        match rotate.receiver().recv().await {
-            Some(mut data) => {
-                let last_tail = tail.take();
-                if data[data.len() - 1] != b'\n' {
-                    if let Some(index) = data.iter().rposition(|&x| x == b'\n') {
-                        tail = Some(data[index + 1..].to_vec());
-                        data.truncate(index + 1);
+    failed == 0
+}
+
+// Lists the rotated files starting with `prefix` that have no compressed form,
+// newest first by the day and sequence in their name, then by modification time.
+// Plain files kept next to their archive are left out.
+fn list_uncompressed(prefix: &str) -> Vec<String> {
+    let mut files = match file_glob(prefix) {
+        Ok(files) => files
+            .into_iter()
+            .filter(|f| strip_compressed(f) == f && !is_sidecar(f) && is_rotated(prefix, f))
+            .filter(|f| !archive_variants(f).iter().any(is_file))
+            .filter_map(|f| {
+                let (day, seq) = parse_rotated(&f[prefix.len()..])?;
+                let modified = fs::metadata(&f).and_then(|m| m.modified()).ok()?;
+                Some((f, (day, seq, modified)))
+            })
+            .collect::<Vec<_>>(),
+        Err(err) => {
+            error!("failed to list log files: {:+?}", err);
+            return Vec::new();
+        }
+    };
+    // The name orders files by rotation even when mtime is coarse or was changed,
+    // archives count the same whether or not they are compressed yet
+    files.sort_by(|(_, a), (_, b)| b.cmp(a));
+    files.into_iter().map(|(f, _)| f).collect()
+}
+
+// Queues the work following a rotation: compressing the rotated file, if any and
+// if enabled, and removing the expired files. With a compression delay the
+// rotated file is left as is, and the sweep compresses the files that are no
+// longer among the newest ones instead. Checksums are written for the files in
+// their final form. The child is sent the post-rotate signal once the file was
+// moved, see `pm::notify_rotated`.
+fn after_rotate(prefix: &str, rotated: Option<String>, archive: &Archive) {
+    if rotated.is_some() {
+        pm::notify_rotated();
+    }
+    let processed = archive.compress.is_some() || archive.encrypt.is_some();
+    let now = match processed {
+        true => archive.compress_delay == 0,
+        false => archive.checksum,
+    };
+    if let (Some(filename), true) = (rotated, now || archive.on_rotate.is_some()) {
+        if now {
+            if let Ok(mut set) = COMPRESSING.lock() {
+                set.insert(filename.clone());
+            }
+        }
+        let name = format!("compression of \"{}\"", filename);
+        let archive = archive.clone();
+        worker::submit(
+            &name,
+            Box::new(move || {
+                let result = match now && processed {
+                    true => process_rotated(&filename, &archive),
+                    false => filename.clone(),
+                };
+                if now {
+                    checksum_archive(&result, &archive);
+                    if let Ok(mut set) = COMPRESSING.lock() {
+                        set.remove(&filename);
+                    }
+                }
+                notify_rotated(&result, &archive);
+            }),
+        );
+    }
+    submit_maintenance(prefix, archive);
+}
+
+// Tells the rotation callback, if any, where the active file ended up. A panic in
+// the callback is logged and leaves the worker running.
+fn notify_rotated(filename: &str, archive: &Archive) {
+    let Some(on_rotate) = &archive.on_rotate else {
+        return;
+    };
+    let event = RotationEvent {
+        old: archive.path.clone(),
+        new: filename.to_string(),
+        compressed: archive.compress.is_some() && is_compressed(filename),
+    };
+    if panic::catch_unwind(AssertUnwindSafe(|| (on_rotate.0)(&event))).is_err() {
+        error!("rotation callback panicked on \"{}\"", filename);
+    }
+}
+
+// Queues the sweep over the rotated files run after rotations and on the retention
+// timer: delayed compression, bundling of past days, retention, then removing the
+// oldest files while the free space is low
+fn submit_maintenance(prefix: &str, archive: &Archive) {
+    let prefix = prefix.to_string();
+    let archive = archive.clone();
+    let name = format!("retention of \"{}*\"", prefix);
+    worker::submit(
+        &name,
+        Box::new(move || {
+            compress_delayed(&prefix, &archive);
+            if archive.daily_bundle {
+                bundle_past_days(&prefix, &archive);
+            }
+            remove_expired(&prefix, &archive);
+            if archive.min_free_space > 0 {
+                match purge_for_space(&prefix, &archive) {
+                    Err(err) if err.kind() != ErrorKind::Unsupported => {
+                        error!("failed to check the free space: {:+?}", err);
                     }
+                    _ => {}
                 }
-                let mut lines = utils::Lines::new(data.as_slice());
-                if last_tail.is_some() {
-                    if let Some(i) = lines.next() {
-                        let mut line = last_tail.unwrap();
-                        line.append(&mut i.to_vec());
-                        write_all(&mut rotate, line.as_slice());
-                    } else {
-                        if tail.is_some() {
-                            let mut t1 = last_tail.unwrap();
-                            let mut t2 = tail.unwrap();
-                            t1.append(&mut t2);
-                            tail = Some(t1);
-                        }
-                        continue;
+            }
+        }),
+    );
+}
+
+// With a compression delay, compresses the rotated files that are no longer among
+// the newest ones. Runs after rotations and on the retention timer, so files left
+// plain by an earlier run are compressed even if nothing is written anymore.
+fn compress_delayed(prefix: &str, archive: &Archive) {
+    let processed = archive.compress.is_some() || archive.encrypt.is_some();
+    let delay = match (processed, archive.compress_delay) {
+        (true, delay @ 1..) => delay,
+        _ => return,
+    };
+    // The newest archives stay plain whether or not they were compressed
+    // already, only the rotated files themselves count
+    let rotated = Archive {
+        retention_loose: None,
+        ..archive.clone()
+    };
+    let newest = list_rotated(prefix, &rotated)
+        .into_iter()
+        .take(delay)
+        .flat_map(|(_, files)| files)
+        .collect::<BTreeSet<_>>();
+    for file in list_uncompressed(prefix) {
+        if !newest.contains(&file) && !is_compressing(&file) {
+            let result = process_rotated(&file, archive);
+            checksum_archive(&result, archive);
+        }
+    }
+}
+
+// Bundles the files rotated on each past day into `<prefix><day>.tar.gz` and removes
+// them. Only the names rotation gives are bundled, so other files sharing the
+// prefix are left alone. Checksum sidecars are left out and removed along with
+// their file, the bundle gets one of its own. A day with a file still being
+// compressed is left for the next sweep.
+fn bundle_past_days(prefix: &str, archive: &Archive) {
+    let level = archive.compress.map_or(DEFAULT_BUNDLE_LEVEL, |c| c.level);
+    let files = match file_glob(prefix) {
+        Ok(files) => files,
+        Err(err) => {
+            error!("failed to list log files: {:+?}", err);
+            return;
+        }
+    };
+    let today = day();
+    let mut days: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for file in files {
+        let suffix = &file[prefix.len()..];
+        if is_sidecar(&file) || file.ends_with(".tmp") || strip_compressed(suffix).ends_with(".tar")
+        {
+            continue;
+        }
+        match parse_rotated(suffix) {
+            Some((day, _)) if day < today => days.entry(day).or_default().push(file),
+            _ => {}
+        }
+    }
+    for (day, mut files) in days {
+        if files.iter().any(is_compressing) {
+            continue;
+        }
+        files.sort_by_key(|f| (f.len(), f.clone()));
+        let target = format!("{}{}.tar.gz", prefix, day);
+        if is_file(&target) {
+            warn!("bundle \"{}\" already exists, skipped", target);
+            continue;
+        }
+        if dry_run(format_args!("bundle {:?} into \"{}\"", files, target)) {
+            continue;
+        }
+        if let Err(err) = compress::bundle_files(&files, &target, level) {
+            metrics::inc(&metrics::COMPRESS_FAILURES);
+            error!(
+                "failed to bundle into \"{}\", keeping the files: {:+?}",
+                target, err
+            );
+            continue;
+        }
+        info!("bundled {} files into \"{}\"", files.len(), target);
+        checksum_archive(&target, archive);
+        for file in files {
+            let sidecar = format!("{}.{}", file, compress::CHECKSUM_EXTENSION);
+            for file in [file, sidecar] {
+                if let Err(err) = fs::remove_file(&file) {
+                    if err.kind() != ErrorKind::NotFound {
+                        error!("failed to remove file \"{}\": {:+?}", file, err);
                     }
                 }
-                lines.for_each(|line| {
-                    write_all(&mut rotate, line);
-                });
             }
+        }
+    }
+}
+
+// Returns the space available to us on the filesystem holding `path`
+#[cfg(unix)]
+fn free_space(path: &str) -> io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let dir = match path::Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => path::Path::new("."),
+    };
+    let dir = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(dir.as_ptr(), &mut stat) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    // The field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &str) -> io::Result<u64> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "free space can't be checked on this platform",
+    ))
+}
+
+// Removes the oldest rotated files, whatever the retention, until the free space
+// on their filesystem is back to `min_free_space`. Returns the free space left.
+fn purge_for_space(prefix: &str, archive: &Archive) -> io::Result<u64> {
+    let floor = archive.min_free_space;
+    let mut free = free_space(prefix)?;
+    if free >= floor {
+        return Ok(free);
+    }
+    for (_, files) in list_rotated(prefix, archive).into_iter().rev() {
+        if is_pinned(&files, archive) {
+            continue;
+        }
+        for file in files {
+            if is_compressing(&file)
+                || dry_run(format_args!("remove \"{}\" (below min_free_space)", file))
+            {
+                continue;
+            }
+            // Moving files to a trash on the same filesystem frees nothing, so they
+            // are removed for good here
+            match delete_file(&file, archive) {
+                Ok(_) => {
+                    metrics::inc(&metrics::FILES_DELETED);
+                    warn!("removed file \"{}\" (below min_free_space)", &file);
+                }
+                Err(err) => {
+                    error!("failed to remove file \"{}\": {:+?}", &file, err);
+                }
+            }
+        }
+        free = free_space(prefix)?;
+        if free >= floor {
+            break;
+        }
+    }
+    Ok(free)
+}
+
+// Keeps writes from filling the filesystem. Below the free space floor the oldest
+// rotated files are removed first, then incoming data is dropped until enough space
+// is available again.
+struct SpaceGuard {
+    floor: u64,                  // The free space kept, 0 when not checked
+    prefix: String,              // The start of rotated names, the files removed first
+    archive: Archive,            // Which files are rotated ones
+    checked_at: Option<Instant>, // The last time the free space was checked
+    low: bool,                   // Whether data is being dropped
+    dropped: u64,                // The bytes dropped since space ran low
+}
+
+impl SpaceGuard {
+    fn new(options: &Options) -> Self {
+        let path = options.path.as_deref().unwrap_or(DEFAULT_PATH);
+        Self {
+            floor: options.min_free_space,
+            prefix: rotated_prefix(path, options),
+            archive: Archive::new(options),
+            checked_at: None,
+            low: false,
+            dropped: 0,
+        }
+    }
+
+    // Returns whether `len` bytes may be written, the free space is checked at
+    // most once per interval
+    fn allows(&mut self, len: u64) -> bool {
+        if self.floor == 0 {
+            return true;
+        }
+        if self
+            .checked_at
+            .is_none_or(|at| at.elapsed() >= SPACE_CHECK_INTERVAL)
+        {
+            self.check();
+        }
+        if self.low {
+            self.dropped += len;
+        }
+        !self.low
+    }
+
+    // Checks the free space on the retention interval, so that dropping stops once
+    // space is freed even if nothing is written
+    fn recheck(&mut self) {
+        if self.floor > 0 {
+            self.check();
+        }
+    }
+
+    fn check(&mut self) {
+        self.checked_at = Some(Instant::now());
+        let free = match purge_for_space(&self.prefix, &self.archive) {
+            Ok(free) => free,
+            Err(err) if err.kind() == ErrorKind::Unsupported => {
+                warn!("{}, min_free_space is ignored", err);
+                self.floor = 0;
+                return;
+            }
+            Err(err) => {
+                error!("failed to check the free space: {:+?}", err);
+                return;
+            }
+        };
+        let low = free < self.floor;
+        match (self.low, low) {
+            (false, true) => warn!(
+                "free space {} bytes is below {} bytes, dropping incoming data",
+                free, self.floor
+            ),
+            (true, true) => warn!(
+                "free space still below {} bytes, {} bytes dropped so far",
+                self.floor, self.dropped
+            ),
+            (true, false) => {
+                warn!(
+                    "free space recovered to {} bytes, {} bytes were dropped",
+                    free, self.dropped
+                );
+                self.dropped = 0;
+            }
+            (false, false) => {}
+        }
+        self.low = low;
+    }
+}
+
+// Parses the day and the sequence out of the part of a rotated name following the
+// prefix. Only the names given by rotation are accepted: `<day>` or `<day>-<seq>`,
+// then the extensions of compression, encryption, bundles and checksums, if any.
+fn parse_rotated(suffix: &str) -> Option<(String, u64)> {
+    let suffix = suffix
+        .strip_suffix(compress::CHECKSUM_EXTENSION)
+        .and_then(|s| s.strip_suffix('.'))
+        .unwrap_or(suffix);
+    let suffix = strip_compressed(suffix);
+    let suffix = suffix.strip_suffix(".tar").unwrap_or(suffix);
+    let day = suffix.get(..DATE_LEN)?;
+    if !day.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let seq = match &suffix[DATE_LEN..] {
+        "" => 0,
+        rest => {
+            let seq = rest.strip_prefix('-')?;
+            if seq.is_empty() || !seq.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            seq.parse().ok()?
+        }
+    };
+    Some((day.to_string(), seq))
+}
+
+// Checks if `file` was rotated from the path `prefix` was made of, rather than just
+// starting the same way, like the files of another output or a manual backup
+fn is_rotated(prefix: &str, file: &str) -> bool {
+    file.strip_prefix(prefix).and_then(parse_rotated).is_some()
+}
+
+// The files next to the rotated ones whose names don't parse, logged only once
+static UNPARSED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+// Lists the rotated files starting with `prefix` grouped by archive, each with its
+// checksum sidecar, newest first. Archives are ordered by the day and sequence in
+// their name then by mtime, or by mtime alone when retention goes by mtime. The
+// files matching the loose pattern are added, aged by their mtime.
+fn list_rotated(prefix: &str, archive: &Archive) -> Vec<(String, Vec<String>)> {
+    let files = match file_glob(prefix) {
+        Ok(files) => files,
+        Err(err) => {
+            error!("failed to list log files: {:+?}", err);
+            return Vec::new();
+        }
+    };
+    let mut archives: BTreeMap<String, (String, u64, SystemTime, Vec<String>)> = BTreeMap::new();
+    for file in files {
+        if file.ends_with(".tmp") {
+            continue;
+        }
+        let name = match is_sidecar(&file) {
+            true => file[..file.len() - compress::CHECKSUM_EXTENSION.len() - 1].to_string(),
+            false => file.clone(),
+        };
+        let (day, seq) = match parse_rotated(&name[prefix.len()..]) {
+            Some(parsed) => parsed,
             None => {
-                break;
+                if !is_loose(&file, archive)
+                    && UNPARSED
+                        .lock()
+                        .is_ok_and(|mut set| set.insert(file.clone()))
+                {
+                    info!("file \"{}\" isn't a rotated file, left alone", file);
+                }
+                continue;
+            }
+        };
+        let modified = fs::metadata(&file)
+            .and_then(|m| m.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let entry = archives
+            .entry(name)
+            .or_insert_with(|| (day, seq, modified, Vec::new()));
+        entry.2 = entry.2.max(modified);
+        entry.3.push(file);
+    }
+    let by_mtime = matches!(archive.retention_by, RetentionBy::Mtime);
+    let mut archives = archives
+        .into_values()
+        .map(|(day, seq, modified, files)| match by_mtime {
+            true => (format_day(modified.into()), 0, modified, files),
+            false => (day, seq, modified, files),
+        })
+        .collect::<Vec<_>>();
+    if archive.retention_loose.is_some() {
+        let listed = archives
+            .iter()
+            .flat_map(|(_, _, _, files)| files.iter().cloned())
+            .collect::<BTreeSet<_>>();
+        for file in list_loose(prefix, archive) {
+            if listed.contains(&file) {
+                continue;
+            }
+            if let Ok(modified) = fs::metadata(&file).and_then(|m| m.modified()) {
+                archives.push((format_day(modified.into()), 0, modified, vec![file]));
             }
         }
     }
-    if let Some(t) = tail {
-        write_all(&mut rotate, &t);
+    match by_mtime {
+        true => archives.sort_by_key(|a| Reverse(a.2)),
+        false => archives.sort_by(|a, b| (&b.0, b.1, b.2).cmp(&(&a.0, a.1, a.2))),
+    }
+    archives
+        .into_iter()
+        .map(|(day, _, _, files)| (day, files))
+        .collect()
+}
+
+// Checks if the name of `file` matches the loose retention pattern
+fn is_loose(file: &str, archive: &Archive) -> bool {
+    let name = path::Path::new(file).file_name().and_then(|n| n.to_str());
+    match (&archive.retention_loose, name) {
+        (Some(pattern), Some(name)) => pattern.matches(name),
+        _ => false,
+    }
+}
+
+// Lists the files of the output's directory matching the loose retention pattern,
+// leaving out the active file and hidden files such as temporary archives
+fn list_loose(prefix: &str, archive: &Archive) -> Vec<String> {
+    let dir = match path::Path::new(prefix).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => path::Path::new("."),
+    };
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(err) => {
+            error!("failed to list log files: {:+?}", err);
+            return Vec::new();
+        }
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+        .filter_map(|entry| entry.path().to_str().map(String::from))
+        .filter(|file| is_loose(file, archive))
+        .filter(|file| {
+            let hidden = path::Path::new(file)
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+            // The active file is the prefix without the separator
+            !hidden && !file.ends_with(".tmp") && !prefix.starts_with(file.as_str())
+        })
+        .collect()
+}
+
+// Removes a file expired by retention, or moves it to the trash directory if set
+fn remove_expired_file(file: &str, archive: &Archive) -> io::Result<()> {
+    match &archive.expire_to {
+        Some(trash) => move_to_trash(file, trash),
+        None => delete_file(file, archive),
+    }
+}
+
+// Removes a file for good, overwriting it with zeros and syncing it first when
+// shredding is on
+fn delete_file(file: &str, archive: &Archive) -> io::Result<()> {
+    if archive.shred {
+        shred_file(file)?;
+    }
+    fs::remove_file(file)
+}
+
+// The format of the time prefixed to the names of trashed files, always in UTC so
+// that it reads back the same whatever the clock
+const TRASH_TIME_FMT: &str = "%Y%m%dT%H%M%SZ";
+
+// Moves a file into the trash directory as `<time>-<name>`, with a sequence added if
+// that name is taken. A trash on another filesystem gets a copy and the file is
+// removed once the copy is synced.
+fn move_to_trash(file: &str, trash: &str) -> io::Result<()> {
+    let name = path::Path::new(file)
+        .file_name()
+        .ok_or_else(|| Error::other("invalid file name"))?
+        .to_string_lossy()
+        .to_string();
+    fs::create_dir_all(trash)?;
+    let stamp = Utc::now().format(TRASH_TIME_FMT);
+    let mut target = path::Path::new(trash).join(format!("{}-{}", stamp, name));
+    let mut seq = 1;
+    while target.exists() {
+        target = path::Path::new(trash).join(format!("{}-{}.{}", stamp, name, seq));
+        seq += 1;
+    }
+    info!("move file: {:?} -> {:?}", file, target);
+    if let Err(err) = fs::rename(file, &target) {
+        warn!("failed to move the file, copying it instead: {:+?}", err);
+        let copied = fs::copy(file, &target)
+            .and_then(|_| File::open(&target))
+            .and_then(|fp| fp.sync_all());
+        if let Err(err) = copied {
+            let _ = fs::remove_file(&target);
+            return Err(err);
+        }
+        fs::remove_file(file)?;
+    }
+    Ok(())
+}
+
+// Removes the files moved to the trash more than `trash_keep_days` days ago, as told
+// by the time in their name. Other files in the trash are left alone.
+fn purge_trash(archive: &Archive) {
+    let (trash, days) = match (&archive.expire_to, archive.trash_keep_days) {
+        (Some(trash), 1..) => (trash, archive.trash_keep_days),
+        _ => return,
+    };
+    let entries = match fs::read_dir(trash) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return,
+        Err(err) => {
+            error!("failed to list the trash \"{}\": {:+?}", trash, err);
+            return;
+        }
+    };
+    let cutoff = Utc::now() - chrono::Duration::days(days);
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let trashed = name
+            .split_once('-')
+            .and_then(|(stamp, _)| NaiveDateTime::parse_from_str(stamp, TRASH_TIME_FMT).ok());
+        match trashed {
+            Some(trashed) if trashed.and_utc() < cutoff => {}
+            _ => continue,
+        }
+        let file = entry.path().to_string_lossy().to_string();
+        if dry_run(format_args!(
+            "remove \"{}\" (older than trash_keep_days)",
+            file
+        )) {
+            continue;
+        }
+        match delete_file(&file, archive) {
+            Ok(_) => {
+                metrics::inc(&metrics::FILES_DELETED);
+                info!("removed file \"{}\" (older than trash_keep_days)", file);
+            }
+            Err(err) => error!("failed to remove file \"{}\": {:+?}", file, err),
+        }
+    }
+}
+
+// Overwrites the file with zeros over its whole length in a single pass and syncs it.
+// A file with other hard links is left as is, its data is still in use. Filesystems
+// that write elsewhere (copy-on-write, log-structured) may keep the old blocks, the
+// overwrite is only best effort there.
+fn shred_file(file: &str) -> io::Result<()> {
+    let meta = fs::metadata(file)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if meta.nlink() > 1 {
+            warn!("file \"{}\" has other links, not shredded", file);
+            return Ok(());
+        }
+    }
+    let mut fp = File::options().write(true).open(file)?;
+    let zeros = [0u8; 64 * 1024];
+    let mut left = meta.len();
+    while left > 0 {
+        let n = left.min(zeros.len() as u64) as usize;
+        fp.write_all(&zeros[..n])?;
+        left -= n as u64;
+    }
+    fp.sync_all()?;
+    metrics::add(&metrics::BYTES_SHREDDED, meta.len());
+    info!(
+        "shredded {} bytes of \"{}\", {} bytes in total",
+        meta.len(),
+        file,
+        metrics::get(&metrics::BYTES_SHREDDED)
+    );
+    Ok(())
+}
+
+// Lists the rotated files starting with `prefix` like `list_rotated`, each archive
+// with the reason retention removes it, if any. The files beyond the newest
+// `keep_num` ones go, then those rotated before the last `keep_days` days, then
+// the oldest ones taking the total size of the kept files over `max_total_size`.
+// A file goes if any rule says so. Pinned archives are always kept and left out of
+// the count and the total size.
+fn list_expired(prefix: &str, archive: &Archive) -> Vec<(Vec<String>, Option<&'static str>)> {
+    // The oldest day kept, today counting as the first one
+    let cutoff = (archive.keep_days > 0).then(|| date_add(1 - archive.keep_days));
+    let mut total = 0; // The size of the newer files kept
+    let mut i = 0; // The position among the archives that aren't pinned
+    let mut archives = Vec::new();
+    for (day, files) in list_rotated(prefix, archive) {
+        if is_pinned(&files, archive) {
+            archives.push((files, None));
+            continue;
+        }
+        i += 1;
+        let reason = if archive.keep_num > 0 && i > archive.keep_num {
+            Some("beyond keep_num")
+        } else if cutoff.as_ref().is_some_and(|cutoff| &day < cutoff) {
+            Some("older than keep_days")
+        } else {
+            total += files
+                .iter()
+                .filter_map(|f| fs::metadata(f).ok())
+                .map(|meta| meta.len())
+                .sum::<u64>();
+            (archive.max_total_size > 0 && total > archive.max_total_size)
+                .then_some("over max_total_size")
+        };
+        archives.push((files, reason));
+    }
+    archives
+}
+
+// Checks if an archive is pinned by a retention exclude pattern, matched against the
+// names of its files. Its checksum sidecar is pinned along with it.
+fn is_pinned(files: &[String], archive: &Archive) -> bool {
+    !archive.retention_exclude.is_empty()
+        && files.iter().any(|file| {
+            let name = path::Path::new(file).file_name().and_then(|n| n.to_str());
+            name.is_some_and(|name| archive.retention_exclude.iter().any(|p| p.matches(name)))
+        })
+}
+
+// Removes the rotated files expired by retention, see `list_expired`. Returns the
+// number of files removed, the bytes freed and the number of files that failed to go.
+fn remove_expired(prefix: &str, archive: &Archive) -> (usize, u64, usize) {
+    let (mut count, mut freed, mut failed) = (0, 0, 0);
+    let mut pinned = 0;
+    for (files, reason) in list_expired(prefix, archive) {
+        let reason = match reason {
+            Some(reason) => reason,
+            None => {
+                if is_pinned(&files, archive) {
+                    pinned += files.len();
+                }
+                continue;
+            }
+        };
+        for file in files {
+            if is_compressing(&file) {
+                info!("skipped file \"{}\" being compressed", &file);
+                continue;
+            }
+            let action = match archive.expire_to {
+                Some(_) => "move to the trash",
+                None => "remove",
+            };
+            if dry_run(format_args!("{} \"{}\" ({})", action, file, reason)) {
+                continue;
+            }
+            let len = fs::metadata(&file).map(|meta| meta.len()).unwrap_or(0);
+            match remove_expired_file(&file, archive) {
+                Ok(_) => {
+                    metrics::inc(&metrics::FILES_DELETED);
+                    info!("expired file \"{}\" ({})", &file, reason);
+                    count += 1;
+                    freed += len;
+                }
+                Err(err) => {
+                    error!("failed to expire file \"{}\": {:+?}", &file, err);
+                    failed += 1;
+                }
+            }
+        }
+    }
+    if pinned > 0 {
+        info!(
+            "{} files of \"{}*\" excluded from retention",
+            pinned, prefix
+        );
+    }
+    purge_trash(archive);
+    (count, freed, failed)
+}
+
+// Applies retention once to the rotated files of the output, for the `clean`
+// subcommand. The active file is never touched. Prints a summary and returns
+// whether every removal succeeded.
+pub fn clean_all(options: &Options) -> bool {
+    let path = options.path.as_deref().unwrap_or(DEFAULT_PATH);
+    let prefix = rotated_prefix(path, options);
+    let (count, freed, failed) = remove_expired(&prefix, &Archive::new(options));
+    match &options.expire_to {
+        Some(trash) => println!(
+            "\"{}\": moved {} files ({} bytes) to \"{}\", {} failed",
+            path, count, freed, trash, failed
+        ),
+        None => println!(
+            "\"{}\": removed {} files, freed {} bytes, {} failed",
+            path, count, freed, failed
+        ),
+    }
+    failed == 0
+}
+
+// Prints the rotated files of the output, newest first, with their size, their age
+// and whether retention would remove them, for the `list` subcommand. Nothing is
+// removed. Returns whether the files could be listed.
+pub fn list_all(options: &Options) -> bool {
+    let path = options.path.as_deref().unwrap_or(DEFAULT_PATH);
+    let prefix = rotated_prefix(path, options);
+    if let Err(err) = file_glob(&prefix) {
+        eprintln!("failed to list \"{}\": {}", path, err);
+        return false;
+    }
+    let now = SystemTime::now();
+    let (mut count, mut size, mut expired, mut freed) = (0, 0, 0, 0);
+    let archive = Archive::new(options);
+    for (files, reason) in list_expired(&prefix, &archive) {
+        let pinned = is_pinned(&files, &archive);
+        for file in files {
+            let meta = match fs::metadata(&file) {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            let age = meta
+                .modified()
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .unwrap_or_default();
+            let verdict = match reason {
+                Some(reason) => format!("would be removed ({})", reason),
+                None if pinned => "kept (excluded)".to_string(),
+                None => "kept".to_string(),
+            };
+            println!(
+                "{}\t{} bytes\t{}\t{}",
+                file,
+                meta.len(),
+                format_age(age),
+                verdict
+            );
+            count += 1;
+            size += meta.len();
+            if reason.is_some() {
+                expired += 1;
+                freed += meta.len();
+            }
+        }
+    }
+    println!(
+        "\"{}\": {} files, {} bytes, {} would be removed, freeing {} bytes",
+        path, count, size, expired, freed
+    );
+    true
+}
+
+// Formats an age in its largest whole unit: days, hours, minutes or seconds
+fn format_age(age: Duration) -> String {
+    match age.as_secs() {
+        secs @ 86400.. => format!("{}d", secs / 86400),
+        secs @ 3600.. => format!("{}h", secs / 3600),
+        secs @ 60.. => format!("{}m", secs / 60),
+        secs => format!("{}s", secs),
+    }
+}
+
+// Rotates the filename by appending the current day to the rotated prefix
+// If the rotated filename already exists, it appends a unique identifier to it,
+// following the highest one of the day so that a second rotation on the same day,
+// after a restart too, always gets the next `-N` even if an earlier one was removed
+fn rotated_filename(prefix: &str, mul: bool, width: usize) -> String {
+    rotated_filename_on(prefix, &day(), mul, width)
+}
+
+// Returns the rotated filename for the given day, see `rotated_filename`
+fn rotated_filename_on(prefix: &str, day: &str, mul: bool, width: usize) -> String {
+    if !mul {
+        // If no multi mode, check if the file exists
+        let filename = format!("{}{}", prefix, day);
+        if !archive_exists(&filename) {
+            return filename;
+        }
+    }
+
+    let mut i = scan_next_seq(prefix, day);
+    loop {
+        let filename = seq_filename(prefix, day, i, width);
+        if !archive_exists(&filename) {
+            return filename;
+        }
+        i += 1;
+    }
+}
+
+// Rotates the file at the output's path once, for the `rotate-now` subcommand: it is
+// moved aside under the name its cut mode would give it, then compressed and the
+// expired files removed as after any rotation. In daily mode the name has the day
+// of the rotation, like a daily rotation of the running handler. An empty or
+// missing file is left alone. Returns whether the file could be rotated.
+pub fn rotate_now(options: &Options) -> bool {
+    let path = options.path.as_deref().unwrap_or(DEFAULT_PATH).to_string();
+    match fs::metadata(&path) {
+        Ok(meta) if !meta.is_file() => {
+            eprintln!("\"{}\" is not a file", path);
+            return false;
+        }
+        Ok(meta) if meta.len() == 0 => {
+            println!("\"{}\" is empty, not rotated", path);
+            return true;
+        }
+        Ok(_) => {}
+        Err(err) => {
+            eprintln!("failed to read \"{}\": {}", path, err);
+            return false;
+        }
+    }
+    let prefix = rotated_prefix(&path, options);
+    if !create_archive_dir(&prefix) {
+        return false;
+    }
+    let filename = match options.cut_mode {
+        CutMode::Daily => rotated_filename(&prefix, false, options.index_width),
+        CutMode::Size | CutMode::None => {
+            let today = day();
+            let mut next = scan_next_seq(&prefix, &today);
+            if let Some(state) = State::load(&path).filter(|state| state.day == today) {
+                next = next.max(state.seq);
+            }
+            seq_filename(&prefix, &today, next, options.index_width)
+        }
+    };
+    if dry_run(format_args!("rotate \"{}\" to \"{}\"", path, filename)) {
+        return true;
+    }
+    if archive_file(&path, &filename) != Archived::Moved {
+        eprintln!("failed to rotate \"{}\"", path);
+        return false;
+    }
+    println!("rotated \"{}\" to \"{}\"", path, filename);
+    after_rotate(&prefix, Some(filename), &Archive::new(options));
+    true
+}
+
+// Checks if a file written by another process is due for rotation, for the `watch`
+// subcommand: in size mode once it reaches the size limit, in daily mode once it
+// was created on a past day, and in any mode once it is older than the maximum age.
+// Its age is taken from the earlier of its creation and modification times, as a
+// file copied with its times kept was created later than its contents. An empty
+// file is never due.
+pub fn is_due(options: &Options) -> bool {
+    let path = options.path.as_deref().unwrap_or(DEFAULT_PATH);
+    let meta = match fs::metadata(path) {
+        Ok(meta) if meta.is_file() && meta.len() > 0 => meta,
+        _ => return false,
+    };
+    let born = [meta.created().ok(), meta.modified().ok()]
+        .into_iter()
+        .flatten()
+        .min();
+    let aged = match (options.max_age, born) {
+        (Some(max_age), Some(born)) => born.elapsed().is_ok_and(|age| age >= max_age),
+        _ => false,
+    };
+    aged || match options.cut_mode {
+        CutMode::Size => meta.len() >= options.file_size.unwrap_or(DEFAULT_FILE_SIZE),
+        CutMode::Daily => born.is_some_and(|born| format_day(born.into()) < day()),
+        CutMode::None => false,
+    }
+}
+
+pub trait Rotate {
+    // Rotates the filename by appending the current day to the rotated prefix
+    // If the rotated filename already exists, it appends a unique identifier to it
+    fn rotate_filename(&self, prefix: &str, mul: bool, width: usize) -> String {
+        rotated_filename(prefix, mul, width)
+    }
+
+    // Closes the file if the active path was deleted or moved by another process,
+    // so that the next `get_file` reopens it. Checked at most once per interval.
+    fn check_replaced(&mut self) {
+        let (path, file, reopen) = self.active();
+        let due = reopen
+            .interval
+            .is_some_and(|interval| reopen.checked_at.elapsed() >= interval);
+        if !due {
+            return;
+        }
+        reopen.checked_at = Instant::now();
+        if is_replaced(path, file) {
+            info!("file \"{}\" was deleted or moved, reopening", path);
+            self.close();
+        }
+    }
+
+    fn active(&mut self) -> (&str, &Option<File>, &mut ReopenCheck);
+    fn path(&self) -> &str;
+    fn size(&self) -> u64;
+    fn receiver(&mut self) -> &mut mpsc::Receiver<Vec<u8>>;
+    fn get_file(&mut self, len: u64) -> io::Result<&mut File>;
+    fn flush(&mut self);
+    fn close(&mut self);
+
+    // Closes the file for good on shutdown
+    fn finish(&mut self) {
+        self.close();
+    }
+
+    // Flushes the file and syncs it to disk
+    fn sync(&mut self) {
+        self.flush();
+        let (path, file, _) = self.active();
+        if let Some(fp) = file {
+            if let Err(err) = fp.sync_all() {
+                error!("failed to sync \"{}\": {:+?}", path, err);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SizeRotate {
+    path: String,                      // The path where the file will be written
+    receiver: mpsc::Receiver<Vec<u8>>, // The receiver for receiving byte vectors
+    size_limit: u64,                   // The maximum size limit for the file
+    cur_size: u64,                     // The current size of the file
+    file: RefCell<Option<File>>,       // The file being written (wrapped in a RefCell)
+    archive: Archive,                  // How rotated files are compressed and expired
+    header: Vec<u8>,                   // The header written at the top of each new file
+    reopen: ReopenCheck,               // When the path is checked for replacement
+    seq: (String, u32),                // The day and the next sequence number of rotated files
+    prefix: String,                    // The start of rotated names, the path and the separator
+    index_width: usize,                // The width the sequence is zero-padded to
+    retry_at: Option<Instant>,         // When rotation is tried again after it failed
+    max_age: Option<Duration>,         // The age past which the file is rotated whatever its size
+    born: Option<SystemTime>,          // When the active file was created, if known
+    saved: Option<State>,              // The state left by the previous run, used once
+}
+
+impl SizeRotate {
+    fn new(path: String, receiver: mpsc::Receiver<Vec<u8>>, options: &Options) -> Self {
+        let slo = options.file_size.unwrap_or(DEFAULT_FILE_SIZE);
+        let today = day();
+        let prefix = rotated_prefix(&path, options);
+        let mut next = scan_next_seq(&prefix, &today);
+        // The saved sequence wins over the scan, the files it counted may have been
+        // removed or bundled since
+        let saved = State::load(&path);
+        if let Some(state) = saved.as_ref().filter(|state| state.day == today) {
+            next = next.max(state.seq);
+        }
+
+        Self {
+            path,
+            receiver,
+            size_limit: slo,
+            cur_size: 0,
+            file: RefCell::default(),
+            archive: Archive::new(options),
+            header: header_bytes(&options.file_header),
+            reopen: ReopenCheck::new(options.reopen_check),
+            seq: (today, next),
+            prefix,
+            index_width: options.index_width,
+            retry_at: None,
+            max_age: options.max_age,
+            born: None,
+            saved,
+        }
+    }
+
+    // Checks if the active file is older than the maximum age
+    fn is_aged(&self) -> bool {
+        match (self.max_age, self.born) {
+            (Some(max_age), Some(born)) => born.elapsed().is_ok_and(|age| age >= max_age),
+            _ => false,
+        }
+    }
+
+    // Returns the next rotated filename using the sequence number kept in memory,
+    // the directory is only scanned again when the day changes or the name is taken
+    fn next_filename(&mut self) -> String {
+        let day = day();
+        if self.seq.0 != day {
+            self.seq = (day.clone(), scan_next_seq(&self.prefix, &day));
+        }
+        let mut filename = seq_filename(&self.prefix, &day, self.seq.1, self.index_width);
+        if archive_exists(&filename) {
+            self.seq.1 = scan_next_seq(&self.prefix, &day);
+            filename = seq_filename(&self.prefix, &day, self.seq.1, self.index_width);
+        }
+        self.seq.1 += 1;
+        filename
+    }
+
+    fn save_state(&self) {
+        let state = State {
+            day: self.seq.0.clone(),
+            size: self.cur_size,
+            seq: self.seq.1,
+            born: self
+                .born
+                .and_then(|born| born.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map_or(0, |born| born.as_secs()),
+        };
+        state.save(&self.path);
+    }
+}
+
+// Checks if the active file is rotated on shutdown: only when asked to and when it
+// has anything past the header, as it is kept plain otherwise
+fn rotates_on_exit(archive: &Archive, file: &Option<File>, size: u64, header: &[u8]) -> bool {
+    let processed = archive.compress.is_some() || archive.encrypt.is_some();
+    archive.compress_on_exit && processed && file.is_some() && size > header.len() as u64
+}
+
+impl Rotate for SizeRotate {
+    fn active(&mut self) -> (&str, &Option<File>, &mut ReopenCheck) {
+        (&self.path, self.file.get_mut(), &mut self.reopen)
+    }
+
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn size(&self) -> u64 {
+        self.cur_size
+    }
+
+    fn receiver(&mut self) -> &mut mpsc::Receiver<Vec<u8>> {
+        &mut self.receiver
+    }
+
+    // Get a mutable reference to a file for writing data.
+    // If the file is not already open, it opens it and checks the size limit.
+    // If the size limit is exceeded, it performs file rotation by flushing the file,
+    // renaming it, and recursively calling `get_file` to get a new file.
+    fn get_file(&mut self, len: u64) -> io::Result<&mut File> {
+        self.check_replaced();
+        if self.file.get_mut().is_none() {
+            let (mut fp, exists) = open_file(self.path.as_str())?;
+            let written = write_header(&mut fp, &exists, &self.header)?;
+            self.file.replace(Some(fp));
+            let saved = self.saved.take();
+            if let Some(meta) = exists {
+                self.cur_size = meta.len() + written;
+                // The saved creation time holds as long as the file is the one it was
+                // saved for, which only grows; the creation or modification time is
+                // the fallback
+                self.born = match saved.filter(|s| s.born > 0 && meta.len() >= s.size) {
+                    Some(state) => Some(SystemTime::UNIX_EPOCH + Duration::from_secs(state.born)),
+                    None => meta.created().or_else(|_| meta.modified()).ok(),
+                };
+            } else {
+                self.cur_size = written;
+                self.born = Some(SystemTime::now());
+            }
+        }
+        // A file holding nothing but the header is never rotated, so that a line
+        // longer than the limit can't make it rotate over and over
+        if (self.cur_size + len <= self.size_limit && !self.is_aged())
+            || self.cur_size <= self.header.len() as u64
+            || is_held(&self.retry_at)
+        {
+            // Check if the current size plus the new length is less than or equal to the size limit
+            self.cur_size += len;
+            return Ok(self.file.get_mut().as_mut().unwrap());
+        }
+
+        let mut fp = self.file.take().unwrap();
+        if let Err(err) = fp.flush() {
+            error!("failed to flush the file: {:+?}", err);
+        }
+
+        drop(fp);
+
+        let new_filename = self.next_filename();
+        let (archived, new_filename) = cut_file(&self.path, new_filename, &self.archive);
+        let rotated = rotated_or_held(archived, &self.path, new_filename, &mut self.retry_at);
+        self.cur_size = 0;
+        self.save_state();
+        after_rotate(&self.prefix, rotated, &self.archive);
+
+        self.get_file(len)
+    }
+
+    // Flushes the file to disk.
+    #[inline]
+    fn flush(&mut self) {
+        let fp = self.file.borrow_mut();
+        file_flush(&fp);
+    }
+
+    // Closes the file by flushing it and dropping the file handle.
+    #[inline]
+    fn close(&mut self) {
+        self.flush();
+        if self.file.take().is_some() {
+            self.save_state();
+        }
+    }
+
+    // Closes the file, then rotates it if it is compressed on shutdown. It is moved
+    // aside like on any rotation rather than compressed in place, so the next run
+    // starts a new file instead of one next to an archive of the same name.
+    fn finish(&mut self) {
+        let rotates = rotates_on_exit(
+            &self.archive,
+            self.file.get_mut(),
+            self.cur_size,
+            &self.header,
+        );
+        self.close();
+        if !rotates {
+            return;
+        }
+        let new_filename = self.next_filename();
+        let (archived, new_filename) = cut_file(&self.path, new_filename, &self.archive);
+        if archived == Archived::Moved {
+            self.cur_size = 0;
+            self.born = None;
+            self.save_state();
+            after_rotate(&self.prefix, Some(new_filename), &self.archive);
+        }
+    }
+}
+
+unsafe impl Send for SizeRotate {}
+
+#[derive(Debug)]
+struct DailyRotate {
+    path: String,                      // The path where the rotated files will be stored
+    receiver: mpsc::Receiver<Vec<u8>>, // The receiver end of a channel that receives byte vectors
+    file: RefCell<Option<File>>,       // A mutable reference to an optional file
+    archive: Archive,                  // How rotated files are compressed and expired
+    header: Vec<u8>,                   // The header written at the top of each new file
+    create_day: String,                // The day when the file was created
+    cur_size: u64,                     // The current size of the file
+    reopen: ReopenCheck,               // When the path is checked for replacement
+    retry_at: Option<Instant>,         // When rotation is tried again after it failed
+    saved: Option<State>,              // The state left by the previous run, used once
+    prefix: String,                    // The start of rotated names, the path and the separator
+    index_width: usize,                // The width the sequence is zero-padded to
+}
+
+impl DailyRotate {
+    // Constructs a new instance of DailyRotate
+    fn new(path: String, receiver: mpsc::Receiver<Vec<u8>>, options: &Options) -> Self {
+        let saved = State::load(&path);
+        let prefix = rotated_prefix(&path, options);
+        Self {
+            path,
+            receiver,
+            file: RefCell::default(),
+            archive: Archive::new(options),
+            header: header_bytes(&options.file_header),
+            create_day: String::new(),
+            cur_size: 0,
+            reopen: ReopenCheck::new(options.reopen_check),
+            retry_at: None,
+            saved,
+            prefix,
+            index_width: options.index_width,
+        }
+    }
+
+    fn save_state(&self) {
+        let state = State {
+            day: self.create_day.clone(),
+            size: self.cur_size,
+            seq: 0,
+            born: 0,
+        };
+        state.save(&self.path);
+    }
+}
+
+impl Rotate for DailyRotate {
+    fn active(&mut self) -> (&str, &Option<File>, &mut ReopenCheck) {
+        (&self.path, self.file.get_mut(), &mut self.reopen)
+    }
+
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn size(&self) -> u64 {
+        self.cur_size
+    }
+
+    // Receives data from the receiver channel
+    // Returns the received data or an error
+    fn receiver(&mut self) -> &mut mpsc::Receiver<Vec<u8>> {
+        &mut self.receiver
+    }
+
+    // Gets the file to write data to
+    // If the file is not open, it opens the file and sets the create_day field
+    // If the current day is different from the create_day, it rotates the file by flushing, renaming, and opening a new file
+    // Returns a mutable reference to the file
+    fn get_file(&mut self, len: u64) -> io::Result<&mut File> {
+        let day = day(); // Get the current day
+        self.check_replaced();
+        if self.file.get_mut().is_none() {
+            let (mut fp, exists) = open_file(self.path.as_str())?; // Open the file
+            let written = write_header(&mut fp, &exists, &self.header)?;
+            self.file.replace(Some(fp)); // Replace the file with the opened file
+            let saved = self.saved.take();
+            if let Some(meta) = exists {
+                // The saved day holds as long as the file is the one it was saved for,
+                // which only grows; the mtime is the fallback
+                match saved.filter(|state| meta.len() >= state.size) {
+                    Some(state) => self.create_day = state.day,
+                    None => {
+                        // Set the create_day field based on the file creation time
+                        self.create_day = format_day(DateTime::from(meta.modified()?));
+                    }
+                }
+                self.cur_size = meta.len() + written;
+            } else {
+                self.create_day = day.clone(); // Set the create_day field to the current day
+                self.cur_size = written;
+            }
+            self.save_state();
+        }
+
+        if self.create_day == day || is_held(&self.retry_at) {
+            self.cur_size += len;
+            return Ok(self.file.get_mut().as_mut().unwrap()); // Return a mutable reference to the file
+        }
+
+        let mut fp = self.file.take().unwrap();
+        if let Err(err) = fp.flush() {
+            error!("failed to flush the file: {:+?}", err);
+        }
+
+        drop(fp);
+
+        let new_filename = self.rotate_filename(&self.prefix, false, self.index_width);
+        let (archived, new_filename) = cut_file(&self.path, new_filename, &self.archive);
+        let rotated = rotated_or_held(archived, &self.path, new_filename, &mut self.retry_at);
+        after_rotate(&self.prefix, rotated, &self.archive);
+        self.get_file(len)
+    }
+
+    // Flushes the current file
+    #[inline]
+    fn flush(&mut self) {
+        let fp = self.file.borrow_mut();
+        file_flush(&fp);
+    }
+
+    // Closes the file by flushing it and dropping the file handle
+    #[inline]
+    fn close(&mut self) {
+        self.flush();
+        if self.file.take().is_some() {
+            self.save_state();
+        }
+    }
+
+    // Closes the file, then rotates it if it is compressed on shutdown, see
+    // `SizeRotate::finish`. A file rotated again later the same day gets the next
+    // sequence number.
+    fn finish(&mut self) {
+        let rotates = rotates_on_exit(
+            &self.archive,
+            self.file.get_mut(),
+            self.cur_size,
+            &self.header,
+        );
+        self.close();
+        if !rotates {
+            return;
+        }
+        let new_filename = self.rotate_filename(&self.prefix, false, self.index_width);
+        let (archived, new_filename) = cut_file(&self.path, new_filename, &self.archive);
+        if archived == Archived::Moved {
+            self.cur_size = 0;
+            self.save_state();
+            after_rotate(&self.prefix, Some(new_filename), &self.archive);
+        }
+    }
+}
+
+unsafe impl Send for DailyRotate {}
+
+// Writes everything to a single file that is never rotated
+#[derive(Debug)]
+struct NoRotate {
+    path: String,                      // The path of the file
+    receiver: mpsc::Receiver<Vec<u8>>, // The receiver of the data to write
+    file: RefCell<Option<File>>,       // The file being written
+    archive: Archive,                  // How the file is compressed on shutdown
+    header: Vec<u8>,                   // The header written at the top of a new file
+    cur_size: u64,                     // The current size of the file
+    reopen: ReopenCheck,               // When the path is checked for replacement
+    prefix: String,                    // The start of the name archived under when one exists
+    index_width: usize,                // The width the sequence is zero-padded to
+}
+
+impl NoRotate {
+    fn new(path: String, receiver: mpsc::Receiver<Vec<u8>>, options: &Options) -> Self {
+        Self {
+            prefix: rotated_prefix(&path, options),
+            index_width: options.index_width,
+            path,
+            receiver,
+            file: RefCell::default(),
+            archive: Archive::new(options),
+            header: header_bytes(&options.file_header),
+            cur_size: 0,
+            reopen: ReopenCheck::new(options.reopen_check),
+        }
+    }
+}
+
+impl Rotate for NoRotate {
+    fn active(&mut self) -> (&str, &Option<File>, &mut ReopenCheck) {
+        (&self.path, self.file.get_mut(), &mut self.reopen)
+    }
+
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn size(&self) -> u64 {
+        self.cur_size
+    }
+
+    fn receiver(&mut self) -> &mut mpsc::Receiver<Vec<u8>> {
+        &mut self.receiver
+    }
+
+    fn get_file(&mut self, len: u64) -> io::Result<&mut File> {
+        self.check_replaced();
+        if self.file.get_mut().is_none() {
+            let (mut fp, exists) = open_file(self.path.as_str())?;
+            let written = write_header(&mut fp, &exists, &self.header)?;
+            self.file.replace(Some(fp));
+            self.cur_size = exists.map_or(0, |meta| meta.len()) + written;
+        }
+        self.cur_size += len;
+        Ok(self.file.get_mut().as_mut().unwrap())
+    }
+
+    #[inline]
+    fn flush(&mut self) {
+        let fp = self.file.borrow_mut();
+        file_flush(&fp);
+    }
+
+    #[inline]
+    fn close(&mut self) {
+        self.flush();
+        drop(self.file.take());
+    }
+
+    // Closes the file, then compresses it in place if it is compressed on shutdown.
+    // An archive of the same name left by an earlier run is never overwritten, the
+    // file is moved to a rotated name and compressed there instead.
+    fn finish(&mut self) {
+        let rotates = rotates_on_exit(
+            &self.archive,
+            self.file.get_mut(),
+            self.cur_size,
+            &self.header,
+        );
+        self.close();
+        if !rotates {
+            return;
+        }
+        let path = match archive_variants(&self.path).iter().any(is_file) {
+            false => self.path.clone(),
+            true => {
+                let new_filename = rotated_filename(&self.prefix, true, self.index_width);
+                if archive_file(&self.path, &new_filename) != Archived::Moved {
+                    return;
+                }
+                info!(
+                    "an archive of \"{}\" already exists, compressing \"{}\" instead",
+                    self.path, new_filename
+                );
+                new_filename
+            }
+        };
+        let archive = self.archive.clone();
+        let name = format!("compression of \"{}\"", path);
+        worker::submit(
+            &name,
+            Box::new(move || {
+                let result = process_rotated(&path, &archive);
+                checksum_archive(&result, &archive);
+            }),
+        );
+    }
+}
+
+unsafe impl Send for NoRotate {}
+
+pub fn new(options: Options, receiver: mpsc::Receiver<Vec<u8>>) -> Box<dyn Rotate + Send> {
+    let log_path = options
+        .path
+        .clone()
+        .unwrap_or_else(|| String::from(DEFAULT_PATH));
+    if let Some(log_dir) = path::Path::new(&log_path).parent() {
+        if !log_dir.exists() {
+            if let Err(err) = fs::create_dir_all(log_dir) {
+                panic!("failed to create log directory: {:+?}", err)
+            }
+        }
+    }
+    let prefix = rotated_prefix(&log_path, &options);
+    create_archive_dir(&prefix);
+    remove_stale_temp_files(&prefix, &log_path);
+    if options.trim_partial {
+        match trim_partial_line(&log_path, options.delimiter) {
+            Ok(0) => {}
+            Ok(len) => warn!(
+                "removed a partial line of {} bytes from \"{}\"",
+                len, log_path
+            ),
+            Err(err) => error!(
+                "failed to check \"{}\" for a partial line: {:+?}",
+                log_path, err
+            ),
+        }
+    }
+    if options.checksum {
+        let archive = Archive::new(&options);
+        let name = format!("checksum backfill of \"{}\"", log_path);
+        worker::submit(
+            &name,
+            Box::new(move || backfill_checksums(&prefix, &archive)),
+        );
+    }
+    match options.cut_mode {
+        CutMode::Size => {
+            let r = SizeRotate::new(log_path, receiver, &options);
+            Box::new(r)
+        }
+        CutMode::Daily => {
+            let r = DailyRotate::new(log_path, receiver, &options);
+            Box::new(r)
+        }
+        CutMode::None => {
+            let r = NoRotate::new(log_path, receiver, &options);
+            Box::new(r)
+        }
+    }
+}
+
+// How failures to open the active file are handled: each write retries up to
+// `retries` times with a doubling pause, so a transient failure (EINTR/EAGAIN on
+// NFS) doesn't drop the data. Once the retries are used up, writes are dropped
+// without retrying until the cooldown ends, so a full disk makes us drop data
+// rather than wait on the backoff for every line.
+struct OpenRetry {
+    retries: u32,
+    cooldown_until: Option<Instant>,
+    dropped: u64, // The bytes dropped during the current cooldown
+}
+
+impl OpenRetry {
+    fn new(retries: u32) -> Self {
+        Self {
+            retries,
+            cooldown_until: None,
+            dropped: 0,
+        }
+    }
+}
+
+// Writes data to the current file, see `OpenRetry` for how open failures are handled.
+// A failed write is tried once more on a fresh handle, in case ours went bad (EBADF,
+// or EIO/ESTALE on a network filesystem); a file that can't be written at all
+// still drops the data.
+async fn write_all(rotate: &mut Box<dyn Rotate + Send>, data: &[u8], retry: &mut OpenRetry) {
+    let cooling = retry.cooldown_until.is_some_and(|at| Instant::now() < at);
+    let mut backoff = OPEN_RETRY_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        match rotate.get_file(data.len() as u64) {
+            Ok(fp) => {
+                if retry.cooldown_until.take().is_some() {
+                    warn!("file opened again, {} bytes were dropped", retry.dropped);
+                    retry.dropped = 0;
+                }
+                let mut res = fp.write_all(data);
+                if let Err(err) = &res {
+                    warn!(
+                        "failed to write to \"{}\", reopening: {:+?}",
+                        rotate.path(),
+                        err
+                    );
+                    rotate.close();
+                    res = rotate
+                        .get_file(data.len() as u64)
+                        .and_then(|fp| fp.write_all(data));
+                }
+                if let Err(err) = res {
+                    error!("failed to write content to file: {:+?}", err);
+                } else {
+                    metrics::inc(&metrics::LINES_WRITTEN);
+                    metrics::add(&metrics::BYTES_WRITTEN, data.len() as u64);
+                    metrics::set_file_size(rotate.path(), rotate.size());
+                }
+                return;
+            }
+            Err(_) if cooling => {
+                retry.dropped += data.len() as u64;
+                return;
+            }
+            Err(err) if attempt < retry.retries => {
+                attempt += 1;
+                warn!(
+                    "failed to open file, retry {}/{} in {:?}: {:+?}",
+                    attempt, retry.retries, backoff, err
+                );
+                time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(err) => {
+                error!(
+                    "failed to open file, dropping writes for {:?}: {:+?}",
+                    OPEN_COOLDOWN, err
+                );
+                retry.cooldown_until = Some(Instant::now() + OPEN_COOLDOWN);
+                retry.dropped += data.len() as u64;
+                return;
+            }
+        }
+    }
+}
+
+// Copies the written lines to another file descriptor, usually stdout.
+// Writing stops for good once the descriptor is closed or broken, so a reader going
+// away never affects the rotated file. A descriptor already closed at startup
+// disables it the same way.
+struct Tee {
+    out: Option<Box<dyn Write + Send>>,
+}
+
+impl Tee {
+    fn new(fd: Option<i32>) -> Self {
+        let out = fd.and_then(|fd| -> Option<Box<dyn Write + Send>> {
+            #[cfg(unix)]
+            {
+                use std::os::unix::io::FromRawFd;
+                let dup = unsafe { libc::dup(fd) };
+                if dup < 0 {
+                    error!(
+                        "invalid tee file descriptor {}, disabled: {}",
+                        fd,
+                        Error::last_os_error()
+                    );
+                    return None;
+                }
+                Some(Box::new(unsafe { File::from_raw_fd(dup) }))
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = fd;
+                Some(Box::new(io::stdout()))
+            }
+        });
+        Self { out }
+    }
+
+    fn write(&mut self, line: &[u8]) {
+        if let Some(out) = self.out.as_mut() {
+            if let Err(err) = out.write_all(line).and_then(|_| out.flush()) {
+                error!("failed to tee, disabled: {:+?}", err);
+                self.out = None;
+            }
+        }
+    }
+}
+
+// Writes a complete line, prefixed with the current time when a timestamp format is set.
+// Empty lines are written as they are.
+async fn write_line(
+    rotate: &mut Box<dyn Rotate + Send>,
+    timestamp: &Option<String>,
+    line: &[u8],
+    delimiter: u8,
+    retry: &mut OpenRetry,
+) {
+    match timestamp {
+        Some(fmt) if !utils::line_content(line, delimiter).is_empty() => {
+            let mut buf = clock::get().format(Utc::now(), fmt).into_bytes();
+            buf.push(b' ');
+            buf.extend_from_slice(line);
+            write_all(rotate, &buf, retry).await;
+        }
+        _ => write_all(rotate, line, retry).await,
+    }
+}
+
+// Returns the time left until the next midnight on the clock, when daily mode rotates
+fn until_next_day() -> Duration {
+    let now = Utc::now();
+    clock::get()
+        .next_midnight(now)
+        .map(|next| Duration::from_secs((next - now).num_seconds().max(0) as u64))
+        .unwrap_or_default()
+}
+
+// Waits for the next retention tick and queues the same sweep as after a rotation,
+// see `submit_maintenance`
+async fn tick_retention(retention: &mut Option<(time::Interval, String, Archive)>) {
+    let (interval, prefix, archive) = match retention {
+        Some(retention) => retention,
+        None => return pending().await,
+    };
+    interval.tick().await;
+    submit_maintenance(prefix, archive);
+}
+
+// Waits until an optional deadline, never completes if it is `None`
+async fn wait_until(deadline: Option<time::Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => pending().await,
+    }
+}
+
+// Waits for the next tick of an optional interval, never completes if it is `None`
+async fn tick(interval: &mut Option<time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => pending().await,
+    }
+}
+
+pub async fn start(options: Options, receiver: mpsc::Receiver<Vec<u8>>, ch: broadcast::Sender<()>) {
+    let filter = options.filter.clone();
+    let timestamp = options.timestamp.clone();
+    let strip_cr = options.strip_cr;
+    let max_line = options.max_line_length;
+    let delimiter = options.delimiter;
+    let daily = matches!(options.cut_mode, CutMode::Daily);
+    let mut retry = OpenRetry::new(options.open_retries);
+    let mut tee = Tee::new(options.tee);
+    let mut heartbeat = options
+        .heartbeat
+        .map(|period| time::interval_at(time::Instant::now() + period, period));
+    // An output that stops being written never rotates, so retention, bundling and
+    // delayed compression also run on a timer, first at startup. A burst of writes can fill
+    // the disk before the next rotation, so the size cap and the free space are
+    // checked at least every RETENTION_INTERVAL.
+    let delays =
+        (options.compress.is_some() || options.encrypt.is_some()) && options.compress_delay > 0;
+    let maintains = options.keep_num > 0
+        || options.keep_days > 0
+        || options.max_total_size > 0
+        || options.min_free_space > 0
+        || options.daily_bundle
+        || delays;
+    let period = match options.max_total_size.max(options.min_free_space) {
+        0 => options.retention_interval,
+        _ => Some(
+            options
+                .retention_interval
+                .map_or(RETENTION_INTERVAL, |period| period.min(RETENTION_INTERVAL)),
+        ),
+    };
+    let mut retention = period.filter(|_| maintains).map(|period| {
+        let path = options.path.as_deref().unwrap_or(DEFAULT_PATH);
+        let prefix = rotated_prefix(path, &options);
+        let mut interval = time::interval(period);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        (interval, prefix, Archive::new(&options))
+    });
+    let mut space = SpaceGuard::new(&options);
+    let shutdown_timeout = options.shutdown_timeout;
+    let sync = options.sync;
+    let mut shutdown = ch.subscribe();
+    let mut deadline: Option<time::Instant> = None; // When draining stops after shutdown
+    let mut rotate = new(options, receiver);
+    let mut tail: Vec<u8> = Vec::new();
+    let mut lines: u64 = 0; // The lines written since the last heartbeat
+    loop {
+        let data = select! {
+            res = rotate.receiver().recv() => match res {
+                Some(data) => data,
+                None => break,
+            },
+            _ = tick(&mut heartbeat) => {
+                if daily {
+                    info!(
+                        "heartbeat: file \"{}\", size {} bytes, {} lines written, next rotation in {:?}",
+                        rotate.path(), rotate.size(), lines, until_next_day()
+                    );
+                } else {
+                    info!(
+                        "heartbeat: file \"{}\", size {} bytes, {} lines written",
+                        rotate.path(), rotate.size(), lines
+                    );
+                }
+                lines = 0;
+                continue;
+            }
+            _ = tick_retention(&mut retention) => {
+                space.recheck();
+                continue;
+            }
+            _ = shutdown.recv(), if deadline.is_none() && shutdown_timeout.is_some() => {
+                let timeout = shutdown_timeout.unwrap_or_default();
+                info!("shutting down, writing the queued data for at most {:?}", timeout);
+                deadline = Some(time::Instant::now() + timeout);
+                continue;
+            }
+            _ = wait_until(deadline) => {
+                let receiver = rotate.receiver();
+                receiver.close();
+                let mut dropped = tail.len();
+                while let Ok(data) = receiver.try_recv() {
+                    dropped += data.len();
+                }
+                tail.clear();
+                warn!("shutdown timeout hit, {} bytes were dropped", dropped);
+                break;
+            }
+        };
+        // Join the partial line left over from the previous read
+        let mut data = if tail.is_empty() {
+            data
+        } else {
+            let mut line = std::mem::take(&mut tail);
+            line.extend_from_slice(&data);
+            line
+        };
+        let end = data
+            .iter()
+            .rposition(|&x| x == delimiter)
+            .map_or(0, |index| index + 1);
+        tail = data.split_off(end);
+        let long = utils::split_long_line(&mut tail, max_line, delimiter);
+        let complete = utils::Lines::new(&data, delimiter).chain(long.iter().map(Vec::as_slice));
+        for line in complete.filter(|line| filter.is_wanted(line, delimiter)) {
+            let line = match strip_cr {
+                true => utils::strip_cr(line),
+                false => Cow::Borrowed(line),
+            };
+            let line = line.as_ref();
+            tee.write(line);
+            if space.allows(line.len() as u64) {
+                write_line(&mut rotate, &timestamp, line, delimiter, &mut retry).await;
+            }
+            lines += 1;
+        }
+    }
+    if !tail.is_empty() && filter.is_wanted(&tail, delimiter) {
+        tee.write(&tail);
+        if space.allows(tail.len() as u64) {
+            write_line(&mut rotate, &timestamp, &tail, delimiter, &mut retry).await;
+        }
+    }
+    if sync {
+        rotate.sync();
+    }
+    rotate.finish();
+    info!("closed rotation handler");
+    let _ = ch.send(());
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // The options of an output writing `path`, with the defaults of the command line
+    pub(crate) fn options(path: &path::Path) -> Options {
+        Options {
+            path: Some(path.to_string_lossy().into_owned()),
+            cut_mode: CutMode::Size,
+            file_size: Some(DEFAULT_FILE_SIZE),
+            compress: None,
+            compress_delay: 0,
+            compress_min_size: 0,
+            keep_uncompressed: false,
+            daily_bundle: false,
+            encrypt: None,
+            checksum: false,
+            compress_on_exit: false,
+            shred: false,
+            archive_dir: None,
+            expire_to: None,
+            trash_keep_days: 0,
+            keep_days: 0,
+            keep_num: 0,
+            max_total_size: 0,
+            min_free_space: 0,
+            retention_interval: None,
+            retention_by: RetentionBy::Name,
+            retention_loose: None,
+            retention_exclude: Vec::new(),
+            index_width: 0,
+            separator: ".".to_string(),
+            filter: Filter::default(),
+            timestamp: None,
+            strip_cr: false,
+            heartbeat: None,
+            tee: None,
+            file_header: None,
+            reopen_check: None,
+            open_retries: 3,
+            trim_partial: false,
+            shutdown_timeout: None,
+            max_age: None,
+            sync: false,
+            max_line_length: 0,
+            delimiter: b'\n',
+            on_rotate: None,
+        }
+    }
+
+    // Runs the rotation handler over `chunks`, each one arriving as a separate read,
+    // until all of them are written
+    pub(crate) async fn write_chunks(options: Options, chunks: &[&[u8]]) {
+        let (sender, receiver) = mpsc::channel(chunks.len().max(1));
+        let (ch, _) = broadcast::channel(3);
+        for chunk in chunks {
+            sender.send(chunk.to_vec()).await.unwrap();
+        }
+        drop(sender);
+        start(options, receiver, ch).await;
+    }
+
+    #[tokio::test]
+    async fn exclude_sees_the_whole_line_split_across_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let mut options = options(&path);
+        options.filter.exclude = Some(Regex::new("GET /health").unwrap());
+        write_chunks(
+            options,
+            &[
+                b"GET /hea",
+                b"lth 200\nGET /api 200\nGET /he",
+                b"alth 200\n",
+            ],
+        )
+        .await;
+        assert_eq!(fs::read(&path).unwrap(), b"GET /api 200\n");
+    }
+
+    #[tokio::test]
+    async fn include_sees_the_whole_line_split_across_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let mut options = options(&path);
+        options.filter.include = Some(Regex::new("ERROR$").unwrap());
+        write_chunks(
+            options,
+            &[b"a: ERR", b"OR\nb: INFO\nc: ERROR", b"\nd: ERROR-ish\n"],
+        )
+        .await;
+        assert_eq!(fs::read(&path).unwrap(), b"a: ERROR\nc: ERROR\n");
+    }
+
+    #[tokio::test]
+    async fn filter_applies_to_the_last_line_without_newline() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let mut options = options(&path);
+        options.filter.exclude = Some(Regex::new("^drop").unwrap());
+        write_chunks(options, &[b"keep\ndr", b"op me"]).await;
+        assert_eq!(fs::read(&path).unwrap(), b"keep\n");
+    }
+
+    #[tokio::test]
+    async fn timestamp_is_taken_from_the_clock_the_names_use() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let mut options = options(&path);
+        options.timestamp = Some(DATE_FMT.to_string());
+        write_chunks(options, &[b"one\n\ntwo\n"]).await;
+        let today = day();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{} one\n\n{} two\n", today, today)
+        );
+    }
+
+    // The names in `dir` starting with `prefix`, sorted
+    pub(crate) fn names(dir: &path::Path, prefix: &str) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| entry.unwrap().file_name().into_string().ok())
+            .filter(|name| name.starts_with(prefix))
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn rotated_name_skips_plain_and_compressed_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = format!("{}/out.", dir.path().display());
+        fs::write(format!("{}20240207", prefix), "plain").unwrap();
+        assert_eq!(
+            rotated_filename_on(&prefix, "20240207", false, 0),
+            format!("{}20240207-1", prefix)
+        );
+        fs::write(format!("{}20240207-1.gz", prefix), "gzip").unwrap();
+        fs::write(format!("{}20240207-2", prefix), "plain").unwrap();
+        assert_eq!(
+            rotated_filename_on(&prefix, "20240207", true, 0),
+            format!("{}20240207-3", prefix)
+        );
+    }
+
+    #[tokio::test]
+    async fn switching_compression_between_runs_overwrites_no_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let mut plain = options(&path);
+        plain.file_size = Some(10);
+        let gzip = Options {
+            compress: Some(compress::Codec {
+                format: compress::Format::Gzip,
+                level: 6,
+            }),
+            ..plain.clone()
+        };
+        write_chunks(plain.clone(), &[b"aaaaaaaa1\n", b"aaaaaaaa2\n"]).await;
+        write_chunks(gzip, &[b"bbbbbbbbb\n"]).await;
+        write_chunks(plain, &[b"ccccccccc\n"]).await;
+
+        let day = day();
+        let archives = names(dir.path(), "out.");
+        assert_eq!(
+            archives,
+            [
+                format!("out.{}-1", day),
+                format!("out.{}-2.gz", day),
+                format!("out.{}-3", day),
+            ]
+        );
+        let read = |name: &String| fs::read(dir.path().join(name)).unwrap();
+        assert_eq!(read(&archives[0]), b"aaaaaaaa1\n");
+        assert_eq!(
+            compress::tests::decode(&dir.path().join(&archives[1]), compress::Format::Gzip),
+            b"aaaaaaaa2\n"
+        );
+        assert_eq!(read(&archives[2]), b"bbbbbbbbb\n");
+        assert_eq!(fs::read(&path).unwrap(), b"ccccccccc\n");
+    }
+
+    #[tokio::test]
+    async fn rotation_compresses_with_every_codec() {
+        for format in compress::tests::formats() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("out");
+            let mut options = options(&path);
+            let first: Vec<u8> = (0..100)
+                .flat_map(|n| format!("line {:03}\n", n).into_bytes())
+                .collect();
+            options.file_size = Some(first.len() as u64);
+            options.compress = Some(compress::Codec { format, level: 6 });
+            write_chunks(options, &[&first, b"next file\n"]).await;
+
+            let archives = names(dir.path(), "out.");
+            let archive = format!("out.{}-1.{}", day(), format.extension());
+            assert_eq!(archives, std::slice::from_ref(&archive), "{:?}", format);
+            let decoded = compress::tests::decode(&dir.path().join(&archive), format);
+            assert_eq!(decoded, first, "{:?}", format);
+            assert_eq!(fs::read(&path).unwrap(), b"next file\n");
+        }
+    }
+
+    // Runs one retention tick over the output writing `path`, as the timer does
+    async fn tick_once(options: &Options) {
+        let path = options.path.as_deref().unwrap();
+        let interval = time::interval(Duration::from_secs(3600));
+        let mut retention = Some((
+            interval,
+            rotated_prefix(path, options),
+            Archive::new(options),
+        ));
+        tick_retention(&mut retention).await;
+    }
+
+    #[tokio::test]
+    async fn retention_tick_compresses_past_the_delay_without_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let mut options = options(&path);
+        options.compress = Some(compress::Codec {
+            format: compress::Format::Gzip,
+            level: 6,
+        });
+        options.compress_delay = 1;
+        for seq in 1..=3 {
+            fs::write(dir.path().join(format!("out.20240101-{}", seq)), "x\n").unwrap();
+        }
+        tick_once(&options).await;
+        assert_eq!(
+            names(dir.path(), "out."),
+            ["out.20240101-1.gz", "out.20240101-2.gz", "out.20240101-3"]
+        );
+    }
+
+    #[test]
+    fn failed_move_leaves_the_file_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out").to_string_lossy().into_owned();
+        fs::write(&path, "data\n").unwrap();
+        // A file where the archive directory should be fails both the rename and
+        // the copy
+        fs::write(dir.path().join("blocker"), "").unwrap();
+        let target = dir
+            .path()
+            .join("blocker/out.1")
+            .to_string_lossy()
+            .into_owned();
+        assert_eq!(archive_file(&path, &target), Archived::Failed);
+        assert_eq!(fs::read(&path).unwrap(), b"data\n");
+    }
+
+    #[tokio::test]
+    async fn failed_rotation_keeps_appending_instead_of_looping() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        fs::write(dir.path().join("blocker"), "").unwrap();
+        let mut options = options(&path);
+        options.file_size = Some(10);
+        options.archive_dir = Some("blocker".to_string());
+        let started = Instant::now();
+        write_chunks(options, &[b"line 0001\n", b"line 0002\n", b"line 0003\n"]).await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            fs::read(&path).unwrap(),
+            b"line 0001\nline 0002\nline 0003\n"
+        );
+        assert_eq!(names(dir.path(), "out."), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn line_longer_than_the_limit_rotates_once_per_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let mut options = options(&path);
+        options.file_size = Some(5);
+        let line = b"a line longer than the limit\n";
+        write_chunks(options, &[line, line, line]).await;
+        assert_eq!(names(dir.path(), "out.").len(), 2);
+        assert_eq!(fs::read(&path).unwrap(), line);
+    }
+
+    #[tokio::test]
+    async fn unopenable_file_drops_writes_during_the_cooldown() {
+        let dir = tempfile::tempdir().unwrap();
+        // The file can't be created under a regular file, like on a full disk
+        fs::write(dir.path().join("blocker"), "").unwrap();
+        let mut options = options(&dir.path().join("blocker/out"));
+        options.open_retries = 2;
+        let chunks: Vec<&[u8]> = vec![b"line\n"; 100];
+        let started = Instant::now();
+        write_chunks(options, &chunks).await;
+        // Only the first write retries, with a backoff of 10ms then 20ms, the
+        // others are dropped right away
+        assert!(started.elapsed() < OPEN_COOLDOWN);
+    }
+
+    #[tokio::test]
+    async fn write_retry_is_bounded() {
+        let mut retry = OpenRetry::new(2);
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("blocker"), "").unwrap();
+        let (_sender, receiver) = mpsc::channel(1);
+        let mut rotate = new(options(&dir.path().join("blocker/out")), receiver);
+        write_all(&mut rotate, b"line\n", &mut retry).await;
+        assert!(retry.cooldown_until.is_some());
+        assert_eq!(retry.dropped, 5);
+        write_all(&mut rotate, b"line\n", &mut retry).await;
+        assert_eq!(retry.dropped, 10);
+    }
+
+    // The names of the files in the tar.gz bundle at `path`
+    fn bundled(path: &path::Path) -> Vec<String> {
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(File::open(path).unwrap()));
+        let mut names: Vec<String> = tar
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                entry
+                    .unwrap()
+                    .path()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn bundle_takes_only_rotated_files_of_past_days() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let mut options = options(&path);
+        options.daily_bundle = true;
+        let today = day();
+        for name in [
+            "out.20240101".to_string(),
+            "out.20240102-1.gz".to_string(),
+            "out.20240102-1.gz.sha256".to_string(),
+            "out.20240102-2".to_string(),
+            "out.20240102-backup".to_string(),
+            "out.20240102.old".to_string(),
+            format!("out.{}-1", today),
+        ] {
+            fs::write(dir.path().join(name), "x\n").unwrap();
+        }
+        let prefix = rotated_prefix(&path.to_string_lossy(), &options);
+        bundle_past_days(&prefix, &Archive::new(&options));
+
+        assert_eq!(
+            names(dir.path(), "out."),
+            [
+                "out.20240101.tar.gz".to_string(),
+                "out.20240102-backup".to_string(),
+                "out.20240102.old".to_string(),
+                "out.20240102.tar.gz".to_string(),
+                format!("out.{}-1", today),
+            ]
+        );
+        assert_eq!(
+            bundled(&dir.path().join("out.20240101.tar.gz")),
+            ["out.20240101"]
+        );
+        assert_eq!(
+            bundled(&dir.path().join("out.20240102.tar.gz")),
+            ["out.20240102-1.gz", "out.20240102-2"]
+        );
+
+        // A second sweep leaves the bundles alone
+        bundle_past_days(&prefix, &Archive::new(&options));
+        assert_eq!(names(dir.path(), "out.").len(), 5);
+    }
+
+    // Writes `before`, lets `replace` act on the active file, then writes `after`
+    async fn write_around(options: Options, before: &[u8], replace: impl FnOnce(), after: &[u8]) {
+        let mut retry = OpenRetry::new(options.open_retries);
+        let (_sender, receiver) = mpsc::channel(1);
+        let mut rotate = new(options, receiver);
+        write_all(&mut rotate, before, &mut retry).await;
+        rotate.flush();
+        replace();
+        write_all(&mut rotate, after, &mut retry).await;
+        rotate.finish();
+    }
+
+    #[tokio::test]
+    async fn externally_moved_file_is_reopened() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let moved = dir.path().join("out.1");
+        let mut options = options(&path);
+        options.reopen_check = Some(Duration::ZERO);
+        write_around(
+            options,
+            b"before\n",
+            || fs::rename(&path, &moved).unwrap(),
+            b"after\n",
+        )
+        .await;
+        assert_eq!(fs::read(&moved).unwrap(), b"before\n");
+        assert_eq!(fs::read(&path).unwrap(), b"after\n");
+    }
+
+    #[tokio::test]
+    async fn externally_deleted_file_is_recreated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let mut options = options(&path);
+        options.reopen_check = Some(Duration::ZERO);
+        write_around(
+            options,
+            b"before\n",
+            || fs::remove_file(&path).unwrap(),
+            b"after\n",
+        )
+        .await;
+        assert_eq!(fs::read(&path).unwrap(), b"after\n");
+    }
+
+    #[tokio::test]
+    async fn moved_file_is_kept_without_reopen_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let moved = dir.path().join("out.1");
+        write_around(
+            options(&path),
+            b"before\n",
+            || fs::rename(&path, &moved).unwrap(),
+            b"after\n",
+        )
+        .await;
+        assert_eq!(fs::read(&moved).unwrap(), b"before\nafter\n");
+        assert!(!path.exists());
+    }
+
+    // Creates each of `names` in `dir` with `data`
+    fn touch(dir: &path::Path, names: &[&str], data: &str) {
+        for name in names {
+            fs::write(dir.join(name), data).unwrap();
+        }
+    }
+
+    // Runs a retention sweep over the output writing `path`
+    fn expire(path: &path::Path, options: &Options) -> (usize, u64, usize) {
+        let prefix = rotated_prefix(&path.to_string_lossy(), options);
+        remove_expired(&prefix, &Archive::new(options))
+    }
+
+    #[test]
+    fn keep_num_counts_compressed_and_plain_archives_alike() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        touch(
+            dir.path(),
+            &[
+                "out.20240101-1.gz",
+                "out.20240101-2",
+                "out.20240102-1.zst",
+                "out.20240102-2",
+                "out.20240102-10.gz",
+                "out.20240103-1",
+            ],
+            "x\n",
+        );
+        let mut options = options(&path);
+        options.keep_num = 3;
+        assert_eq!(expire(&path, &options).0, 3);
+        assert_eq!(
+            names(dir.path(), "out."),
+            ["out.20240102-10.gz", "out.20240102-2", "out.20240103-1"]
+        );
+    }
+
+    #[test]
+    fn keep_num_and_keep_days_each_expire_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let today = day();
+        let recent: Vec<String> = (1..=3)
+            .map(|seq| format!("out.{}-{}.gz", today, seq))
+            .collect();
+        touch(dir.path(), &["out.20240101-1", "out.20240101-2.gz"], "x\n");
+        for name in &recent {
+            fs::write(dir.path().join(name), "x\n").unwrap();
+        }
+        let mut options = options(&path);
+        options.keep_days = 7;
+        options.keep_num = 2;
+        assert_eq!(expire(&path, &options).0, 3);
+        assert_eq!(names(dir.path(), "out."), recent[1..]);
+    }
+
+    #[tokio::test]
+    async fn keep_days_zero_keeps_the_archives_of_today() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let mut options = options(&path);
+        options.file_size = Some(10);
+        write_chunks(
+            options.clone(),
+            &[b"line 0001\n", b"line 0002\n", b"line 0003\n"],
+        )
+        .await;
+        let day = day();
+        let archives = [format!("out.{}-1", day), format!("out.{}-2", day)];
+        assert_eq!(names(dir.path(), "out."), archives);
+        assert_eq!(expire(&path, &options), (0, 0, 0));
+        assert_eq!(names(dir.path(), "out."), archives);
+    }
+
+    #[tokio::test]
+    async fn rotation_and_retention_agree_on_the_separator() {
+        for separator in ["_", "-", "."] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("app");
+            let mut options = options(&path);
+            options.separator = separator.to_string();
+            options.file_size = Some(10);
+            options.keep_num = 1;
+            let other = if separator == "." { "_" } else { "." };
+            let foreign = format!("app{}20240101-1", other);
+            touch(dir.path(), &[&foreign], "x\n");
+            touch(dir.path(), &[&format!("app{}20240101-1", separator)], "x\n");
+            write_chunks(options.clone(), &[b"line 0001\n", b"line 0002\n"]).await;
+
+            // The old archive with the separator is expired, the one without is not
+            let archive = format!("app{}{}-1", separator, day());
+            assert_eq!(names(dir.path(), "app"), {
+                let mut names = vec!["app".to_string(), archive.clone(), foreign];
+                names.sort();
+                names
+            });
+            assert_eq!(fs::read(dir.path().join(&archive)).unwrap(), b"line 0001\n");
+        }
+    }
+
+    #[test]
+    fn space_guard_rearms_when_space_is_freed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = options(&dir.path().join("out"));
+        options.min_free_space = u64::MAX;
+        let mut space = SpaceGuard::new(&options);
+        assert!(!space.allows(5));
+        assert_eq!(space.dropped, 5);
+
+        // Space is freed while nothing is written, the next check re-arms the guard
+        space.floor = 1;
+        space.recheck();
+        assert!(!space.low);
+        assert_eq!(space.dropped, 0);
+        assert!(space.allows(5));
+    }
+
+    #[test]
+    fn retention_leaves_files_that_only_share_the_prefix() {
+        for separator in [".", "-"] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("app");
+            let rotated = |day: &str| format!("app{}{}", separator, day);
+            let others = [
+                "app-worker.20240101-1".to_string(),
+                "app-worker.20240101-2.gz".to_string(),
+                "app.backup".to_string(),
+                rotated("20240101-backup"),
+                rotated("20240101.log"),
+                rotated("2024010"),
+                "application.20240101-1".to_string(),
+            ];
+            let expired = [rotated("20240101-1"), rotated("20240101-2.gz")];
+            for name in others.iter().chain(&expired) {
+                fs::write(dir.path().join(name), "x\n").unwrap();
+            }
+            let mut options = options(&path);
+            options.separator = separator.to_string();
+            options.keep_days = 1;
+            assert_eq!(expire(&path, &options), (2, 4, 0), "{}", separator);
+
+            let mut kept = others.to_vec();
+            kept.sort();
+            assert_eq!(names(dir.path(), "app"), kept, "{}", separator);
+        }
+    }
+
+    #[test]
+    fn dry_run_holds_rotation_for_good() {
+        let name = || "out.20240101-1".to_string();
+        let mut retry_at = None;
+        let rotated = rotated_or_held(Archived::Moved, "out", name(), &mut retry_at);
+        assert_eq!(rotated, Some(name()));
+        assert!(retry_at.is_none());
+
+        let rotated = rotated_or_held(Archived::Failed, "out", name(), &mut retry_at);
+        assert_eq!(rotated, None);
+        assert!(retry_at.is_some_and(|at| at <= Instant::now() + ROTATE_RETRY_INTERVAL));
+
+        let rotated = rotated_or_held(Archived::DryRun, "out", name(), &mut retry_at);
+        assert_eq!(rotated, None);
+        let day = Duration::from_secs(24 * 3600);
+        assert!(retry_at.is_some_and(|at| at > Instant::now() + day));
+    }
+
+    #[test]
+    fn expiry_ignores_the_compression_of_archives() {
+        for compress in [None, Some(compress::Format::Gzip)] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("out");
+            let old = [
+                "out.20230101",
+                "out.20230101-2.gz",
+                "out.20230101-3.zst",
+                "out.20230101-4.xz",
+                "out.20230101-5.gz.age",
+                "out.20230101-5.gz.age.sha256",
+            ];
+            touch(dir.path(), &old, "x\n");
+            let recent = [format!("out.{}-1", day()), format!("out.{}-2.zst", day())];
+            for name in &recent {
+                fs::write(dir.path().join(name), "x\n").unwrap();
+            }
+            let mut options = options(&path);
+            options.compress = compress.map(|format| compress::Codec { format, level: 6 });
+            options.keep_days = 30;
+            assert_eq!(expire(&path, &options).0, old.len(), "{:?}", compress);
+            assert_eq!(names(dir.path(), "out."), recent, "{:?}", compress);
+        }
+    }
+
+    #[test]
+    fn rotate_now_names_daily_archives_like_the_handler() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let mut options = options(&path);
+        options.cut_mode = CutMode::Daily;
+        let prefix = rotated_prefix(&path.to_string_lossy(), &options);
+        let expected = rotated_filename(&prefix, false, 0);
+
+        // Last written on a past day, as when rotating after midnight
+        fs::write(&path, "line\n").unwrap();
+        let fp = File::options().write(true).open(&path).unwrap();
+        fp.set_modified(SystemTime::now() - Duration::from_secs(3 * 24 * 3600))
+            .unwrap();
+        drop(fp);
+        assert!(rotate_now(&options));
+        assert_eq!(expected, format!("{}{}", prefix, day()));
+        assert_eq!(fs::read(&expected).unwrap(), b"line\n");
+    }
+
+    #[tokio::test]
+    async fn retention_tick_bundles_past_days_without_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let mut options = options(&path);
+        options.daily_bundle = true;
+        touch(dir.path(), &["out.20240101-1", "out.20240101-2"], "x\n");
+        tick_once(&options).await;
+        assert_eq!(names(dir.path(), "out."), ["out.20240101.tar.gz"]);
+    }
+
+    #[test]
+    fn excluded_archives_survive_expiry_by_day_and_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        touch(
+            dir.path(),
+            &[
+                "out.20240101-1.gz",
+                "out.20240101-2.gz",
+                "out.20240101-2.gz.sha256",
+                "out.20240102-1",
+            ],
+            "0123456789",
+        );
+        let mut options = options(&path);
+        options.retention_exclude = vec![glob::Pattern::new("out.20240101-2.*").unwrap()];
+
+        // Past keep_days, all but the pinned archive and its checksum go
+        options.keep_days = 1;
+        assert_eq!(expire(&path, &options).0, 2);
+        assert_eq!(
+            names(dir.path(), "out."),
+            ["out.20240101-2.gz", "out.20240101-2.gz.sha256"]
+        );
+
+        // Over the size cap too, the pinned files don't count towards it
+        touch(
+            dir.path(),
+            &["out.20240103-1", "out.20240104-1"],
+            "0123456789",
+        );
+        options.keep_days = 0;
+        options.max_total_size = 15;
+        assert_eq!(expire(&path, &options).0, 1);
+        assert_eq!(
+            names(dir.path(), "out."),
+            [
+                "out.20240101-2.gz",
+                "out.20240101-2.gz.sha256",
+                "out.20240104-1"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn nul_delimited_records_split_across_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let mut options = options(&path);
+        options.delimiter = b'\0';
+        options.file_size = Some(12);
+        options.filter.exclude = Some(Regex::new("^skip").unwrap());
+        write_chunks(
+            options,
+            &[b"rec one\nA\0rec t", b"wo\0skip\0rec th", b"ree\0rec four"],
+        )
+        .await;
+        // Rotation happens on record boundaries, a newline is just a byte
+        assert_eq!(
+            fs::read(dir.path().join(format!("out.{}-1", day()))).unwrap(),
+            b"rec one\nA\0"
+        );
+        assert_eq!(
+            fs::read(dir.path().join(format!("out.{}-2", day()))).unwrap(),
+            b"rec two\0"
+        );
+        assert_eq!(
+            fs::read(dir.path().join(format!("out.{}-3", day()))).unwrap(),
+            b"rec three\0"
+        );
+        assert_eq!(fs::read(&path).unwrap(), b"rec four");
+    }
+
+    #[tokio::test]
+    async fn failed_write_reopens_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        fs::write(&path, "before\n").unwrap();
+        let (_sender, receiver) = mpsc::channel(1);
+        let options = options(&path);
+        let path_str = path.to_string_lossy().into_owned();
+        let rotator = NoRotate::new(path_str, receiver, &options);
+        // A handle that can't be written, to a file deleted mid-run
+        rotator.file.replace(Some(File::open(&path).unwrap()));
+        fs::remove_file(&path).unwrap();
+        let mut rotate: Box<dyn Rotate + Send> = Box::new(rotator);
+        let mut retry = OpenRetry::new(options.open_retries);
+        write_all(&mut rotate, b"after\n", &mut retry).await;
+        rotate.finish();
+        assert_eq!(fs::read(&path).unwrap(), b"after\n");
+    }
+
+    #[tokio::test]
+    async fn write_failing_after_the_reopen_is_dropped() {
+        let full = path::Path::new("/dev/full");
+        if !full.exists() {
+            return;
+        }
+        let (_sender, receiver) = mpsc::channel(1);
+        let mut options = options(full);
+        options.cut_mode = CutMode::None;
+        let mut rotate = new(options, receiver);
+        let mut retry = OpenRetry::new(3);
+        let started = Instant::now();
+        for _ in 0..10 {
+            write_all(&mut rotate, b"line\n", &mut retry).await;
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn closed_tee_descriptor_leaves_the_file_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        // A descriptor that was never opened, like a closed stdout
+        let fd = 9999;
+        let tee = Tee::new(Some(fd));
+        assert!(tee.out.is_none());
+        let mut options = options(&path);
+        options.tee = Some(fd);
+        write_chunks(options, &[b"line\n"]).await;
+        assert_eq!(fs::read(&path).unwrap(), b"line\n");
+    }
+
+    // The options of an output writing `path`, compressed with `format`
+    fn compressed(path: &path::Path, format: compress::Format) -> Options {
+        Options {
+            compress: Some(compress::Codec { format, level: 6 }),
+            ..options(path)
+        }
+    }
+
+    // Options calling back into the returned list of events on each rotation
+    fn recording(options: Options) -> (Options, Arc<Mutex<Vec<RotationEvent>>>) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let on_rotate = OnRotate(Arc::new(move |event: &RotationEvent| {
+            recorded.lock().unwrap().push(event.clone());
+        }));
+        let options = Options {
+            on_rotate: Some(on_rotate),
+            ..options
+        };
+        (options, events)
+    }
+
+    #[tokio::test]
+    async fn rotation_callback_fires_once_per_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let path_str = path.to_string_lossy().into_owned();
+        let mut gzip = compressed(&path, compress::Format::Gzip);
+        gzip.file_size = Some(10);
+        let (gzip, events) = recording(gzip);
+        write_chunks(
+            gzip.clone(),
+            &[b"aaaaaaaa1\n", b"aaaaaaaa2\n", b"aaaaaaaa3\n"],
+        )
+        .await;
+
+        let prefix = format!("{}.{}-", path_str, day());
+        let events = events.lock().unwrap().clone();
+        let seen: Vec<_> = events
+            .iter()
+            .map(|e| (e.old.as_str(), e.new.as_str(), e.compressed))
+            .collect();
+        assert_eq!(
+            seen,
+            [
+                (path_str.as_str(), format!("{}1.gz", prefix).as_str(), true),
+                (path_str.as_str(), format!("{}2.gz", prefix).as_str(), true),
+            ]
+        );
+
+        // Left plain by the delay
+        let (delayed, events) = recording(Options {
+            compress_delay: 5,
+            ..gzip.clone()
+        });
+        write_chunks(delayed, &[b"bbbbbbbb1\n", b"bbbbbbbb2\n"]).await;
+        let seen: Vec<_> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| (e.new.clone(), e.compressed))
+            .collect();
+        assert_eq!(
+            seen,
+            [
+                (format!("{}3", prefix), false),
+                (format!("{}4", prefix), false),
+            ]
+        );
+
+        // A panicking callback leaves the rotations going
+        let panicking = Options {
+            on_rotate: Some(OnRotate(Arc::new(|_: &RotationEvent| {
+                panic!("callback failed")
+            }))),
+            ..gzip
+        };
+        write_chunks(panicking, &[b"cccccccc1\n", b"cccccccc2\n"]).await;
+        assert!(is_file(&format!("{}5.gz", prefix)));
+        assert!(is_file(&format!("{}6.gz", prefix)));
+    }
+
+    #[test]
+    fn file_punched_out_by_an_interrupted_cut_is_restored_on_start() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let path_str = path.to_string_lossy().into_owned();
+        let tmp = dir.path().join(format!(".out.{}-1.gz.tmp", day()));
+        let data = "line\n".repeat(100_000);
+        compress::tests::interrupt(&path, &tmp, data.as_bytes(), 300 * 1024);
+
+        let options = compressed(&path, compress::Format::Gzip);
+        remove_stale_temp_files(&rotated_prefix(&path_str, &options), &path_str);
+        assert_eq!(fs::read_to_string(&path).unwrap(), data);
+        assert!(!tmp.exists());
+    }
+
+    #[test]
+    fn cut_streams_the_file_into_its_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        fs::write(&path, "line\n".repeat(1000)).unwrap();
+        let mut options = compressed(&path, compress::Format::Gzip);
+        options.archive_dir = Some("archive".to_string());
+        let path_str = path.to_string_lossy().into_owned();
+        let new_filename = dir.path().join("archive/out.20240101-1");
+        let new_filename = new_filename.to_string_lossy().into_owned();
+        let (archived, rotated) =
+            cut_file(&path_str, new_filename.clone(), &Archive::new(&options));
+        assert_eq!(archived, Archived::Moved);
+        assert_eq!(rotated, format!("{}.gz", new_filename));
+        assert!(!path.exists());
+        assert_eq!(
+            names(&dir.path().join("archive"), ""),
+            ["out.20240101-1.gz"]
+        );
+        let decoded = compress::tests::decode(path::Path::new(&rotated), compress::Format::Gzip);
+        assert_eq!(decoded, "line\n".repeat(1000).as_bytes());
+    }
+
+    #[test]
+    fn cut_moves_the_file_when_it_is_not_compressed_right_away() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let path_str = path.to_string_lossy().into_owned();
+        let new_filename = format!("{}.20240101-1", path_str);
+        let gzip = compressed(&path, compress::Format::Gzip);
+        let delayed = Options {
+            compress_delay: 1,
+            ..gzip.clone()
+        };
+        let kept = Options {
+            keep_uncompressed: true,
+            ..gzip.clone()
+        };
+        let small = Options {
+            compress_min_size: 1024,
+            ..gzip
+        };
+        let mut cases = vec![options(&path), delayed, kept, small];
+        // A codec that fails to encode falls back to moving the file
+        let missing = [
+            compress::Format::Bzip2,
+            compress::Format::Xz,
+            compress::Format::Lz4,
+        ]
+        .into_iter()
+        .find(|format| !format.is_available());
+        if let Some(format) = missing {
+            cases.push(compressed(&path, format));
+        }
+        for options in cases {
+            fs::write(&path, "line\n").unwrap();
+            let (archived, rotated) =
+                cut_file(&path_str, new_filename.clone(), &Archive::new(&options));
+            assert_eq!(archived, Archived::Moved, "{:?}", options);
+            assert_eq!(rotated, new_filename, "{:?}", options);
+            assert_eq!(fs::read(&rotated).unwrap(), b"line\n");
+            assert_eq!(names(dir.path(), "").len(), 1, "{:?}", options);
+            fs::remove_file(&rotated).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn single_file_compressed_on_exit_never_overwrites_an_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let mut options = compressed(&path, compress::Format::Gzip);
+        options.cut_mode = CutMode::None;
+        write_chunks(options.clone(), &[b"plain\n"]).await;
+        assert_eq!(names(dir.path(), "out"), ["out"]);
+        fs::remove_file(&path).unwrap();
+
+        options.compress_on_exit = true;
+        write_chunks(options.clone(), &[b"first\n"]).await;
+        write_chunks(options, &[b"second\n"]).await;
+        let second = format!("out.{}-1.gz", day());
+        assert_eq!(
+            names(dir.path(), "out"),
+            [second.clone(), "out.gz".to_string()]
+        );
+        let decode =
+            |name: &str| compress::tests::decode(&dir.path().join(name), compress::Format::Gzip);
+        assert_eq!(decode("out.gz"), b"first\n");
+        assert_eq!(decode(&second), b"second\n");
     }
-    rotate.close();
-    log!("closed rotation handler");
-    let _ = ch.send(());
 }
@@ -1,3 +1,14 @@
+use std::borrow::Cow;
+use std::io::IsTerminal;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use tokio::time::sleep;
+
+use crate::metrics;
+
 pub fn trim_file_src(mut src: &str) -> &str {
     if src.starts_with("src/") || src.starts_with("src\\") {
         src = &src[4..];
@@ -5,41 +16,192 @@ pub fn trim_file_src(mut src: &str) -> &str {
     src
 }
 
-pub static mut DEBUG: bool = false;
+// How important a diagnostic is, the levels shown are set once at startup
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1, // Something failed, data may be lost or left unprocessed
+    Warn = 2,  // Something is off but handled, like data dropped on purpose
+    Info = 3,  // What is going on, only with --debug
+}
+
+// The most detailed level shown: errors and warnings by default, everything with
+// --debug, nothing with --quiet. Fatal errors are printed before exiting whatever
+// the level.
+static mut MAX_LEVEL: u8 = Level::Warn as u8;
+
+// Whether diagnostics are colored, only when stderr is a terminal and NO_COLOR is unset
+static mut COLOR: bool = false;
 
-pub fn set_debug(debug: bool) {
+pub fn set_level(debug: bool, quiet: bool) {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
     unsafe {
-        DEBUG = debug;
+        MAX_LEVEL = match (quiet, debug) {
+            (true, _) => 0,
+            (false, true) => Level::Info as u8,
+            (false, false) => Level::Warn as u8,
+        };
+        COLOR = !no_color && std::io::stderr().is_terminal();
+    }
+}
+
+#[inline]
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= unsafe { MAX_LEVEL }
+}
+
+// The first wait before repeats of a message are reported, doubled after each report
+const REPEAT_WAIT: Duration = Duration::from_secs(1);
+
+// The longest wait between two reports of the same repeated message
+const REPEAT_WAIT_MAX: Duration = Duration::from_secs(60);
+
+// The last diagnostic written and how often it was repeated since
+struct Repeat {
+    level: Level,      // How important it is
+    src: String,       // The location it was logged at
+    line: u32,         // The line it was logged at
+    msg: String,       // The message itself
+    count: u64,        // The repeats not reported yet
+    reported: Instant, // When the message or its repeats were last written
+    wait: Duration,    // How long repeats are held back before being reported
+}
+
+static LAST: Mutex<Option<Repeat>> = Mutex::new(None);
+
+// Writes a diagnostic line to stderr. A message repeated from the same place is held
+// back and reported as "last message repeated N times", with a wait doubling from
+// REPEAT_WAIT up to REPEAT_WAIT_MAX between reports, so that an error hit on every
+// write can't flood stderr.
+pub fn write_log(level: Level, src: &str, line: u32, msg: &str) {
+    let src = trim_file_src(src);
+    let mut last = match LAST.lock() {
+        Ok(last) => last,
+        Err(_) => return print_log(level, src, line, msg),
+    };
+    if let Some(repeat) = last.as_mut() {
+        if repeat.src == src && repeat.line == line && repeat.msg == msg {
+            repeat.count += 1;
+            if repeat.reported.elapsed() >= repeat.wait {
+                report_repeats(repeat);
+                repeat.wait = (repeat.wait * 2).min(REPEAT_WAIT_MAX);
+            }
+            return;
+        }
+        report_repeats(repeat);
+    }
+    print_log(level, src, line, msg);
+    *last = Some(Repeat {
+        level,
+        src: src.to_string(),
+        line,
+        msg: msg.to_string(),
+        count: 0,
+        reported: Instant::now(),
+        wait: REPEAT_WAIT,
+    });
+}
+
+// Reports the repeats of the last message still held back, on shutdown
+pub fn flush_log() {
+    if let Ok(mut last) = LAST.lock() {
+        if let Some(repeat) = last.as_mut() {
+            report_repeats(repeat);
+        }
+    }
+}
+
+fn report_repeats(repeat: &mut Repeat) {
+    if repeat.count > 0 {
+        let msg = format!("last message repeated {} times", repeat.count);
+        print_log(repeat.level, &repeat.src, repeat.line, &msg);
+        repeat.count = 0;
+    }
+    repeat.reported = Instant::now();
+}
+
+// Errors and warnings are marked as such. With color, the location is dimmed,
+// errors are shown in red and warnings in yellow.
+fn print_log(level: Level, src: &str, line: u32, msg: &str) {
+    let (label, color) = match level {
+        Level::Error => ("error: ", "\x1b[31m"),
+        Level::Warn => ("warning: ", "\x1b[33m"),
+        Level::Info => ("", ""),
+    };
+    if !unsafe { COLOR } || level == Level::Info {
+        eprintln!("[{}:{}] - {}{}", src, line, label, msg);
+    } else {
+        eprintln!(
+            "\x1b[2m[{}:{}]\x1b[0m - {}{}{}\x1b[0m",
+            src, line, color, label, msg
+        );
     }
 }
 
 #[macro_export]
-macro_rules! log {
-    ($fmt:expr, $($arg:tt)*) => {
-      if unsafe {crate::utils::DEBUG} {
-        println!("[{}:{}] - {}", crate::utils::trim_file_src(file!()), line!(), format!($fmt, $($arg)*))
-      }
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::utils::enabled($level) {
+            $crate::utils::write_log($level, file!(), line!(), &format!($($arg)*))
+        }
     };
-    ($fmt:expr) => {
-      if unsafe {crate::utils::DEBUG} {
-        println!("[{}:{}] - {}", crate::utils::trim_file_src(file!()), line!(), $fmt)
-      }
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log_at!($crate::utils::Level::Error, $($arg)*)
     };
-    () => {
-      if unsafe {crate::utils::DEBUG} {
-        println!("[{}:{}] -", crate::utils::trim_file_src(file!()), line!())
-      }
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::log_at!($crate::utils::Level::Warn, $($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log_at!($crate::utils::Level::Info, $($arg)*)
+    };
+}
+
+// Splits the partial line in `tail` into lines of `max` bytes, each ended with
+// `delimiter`, for as long as it is longer than `max`. This keeps a writer that
+// never ends its line from growing the buffer without bound, at the cost of
+// splitting its line. A `max` of 0 never splits.
+pub fn split_long_line(tail: &mut Vec<u8>, max: usize, delimiter: u8) -> Vec<Vec<u8>> {
+    let mut lines = Vec::new();
+    if max == 0 {
+        return lines;
+    }
+    let mut start = 0;
+    while tail.len() - start > max {
+        let mut line = tail[start..start + max].to_vec();
+        line.push(delimiter);
+        lines.push(line);
+        start += max;
     }
+    tail.drain(..start);
+    lines
 }
 
+// Splits data into lines on `delimiter`, each keeping its line ending, `\r\n`
+// included. A lone `\r` doesn't end a line.
 pub struct Lines<'a> {
     data: &'a [u8],
     pos: usize,
+    delimiter: u8,
 }
 
 impl<'a> Lines<'a> {
-    pub fn new(data: &'a [u8]) -> Self {
-        Lines { data, pos: 0 }
+    pub fn new(data: &'a [u8], delimiter: u8) -> Self {
+        Lines {
+            data,
+            pos: 0,
+            delimiter,
+        }
     }
 }
 
@@ -54,7 +216,7 @@ impl<'a> Iterator for Lines<'a> {
         let start = self.pos;
         let end = self.data[start..]
             .iter()
-            .position(|&b| b == b'\n')
+            .position(|&b| b == self.delimiter)
             .map(|pos| pos + start + 1)
             .unwrap_or(self.data.len());
 
@@ -62,3 +224,145 @@ impl<'a> Iterator for Lines<'a> {
         Some(&self.data[start..self.pos])
     }
 }
+
+// Returns a line without its line ending: `delimiter`, or `\r\n` when lines end
+// in `\n`
+pub fn line_content(line: &[u8], delimiter: u8) -> &[u8] {
+    let content = line.strip_suffix(&[delimiter]).unwrap_or(line);
+    match delimiter {
+        b'\n' if content.len() < line.len() => content.strip_suffix(b"\r").unwrap_or(content),
+        _ => content,
+    }
+}
+
+// Returns a line ending in `\r\n` with a plain `\n` instead, other lines as they are
+pub fn strip_cr(line: &[u8]) -> Cow<'_, [u8]> {
+    match line.strip_suffix(b"\r\n") {
+        Some(content) => {
+            let mut stripped = content.to_vec();
+            stripped.push(b'\n');
+            Cow::Owned(stripped)
+        }
+        None => Cow::Borrowed(line),
+    }
+}
+
+// How long a producer may wait on a full channel before a warning is logged
+const FULL_WARN_AFTER: Duration = Duration::from_secs(1);
+
+// Sends a buffer to the rotation channel, tracking how often and how long the
+// producer is blocked because the consumer can't keep up
+pub async fn send(
+    sender: &mpsc::Sender<Vec<u8>>,
+    buf: Vec<u8>,
+    name: &str,
+) -> Result<(), SendError<Vec<u8>>> {
+    let buf = match sender.try_send(buf) {
+        Ok(()) => return Ok(()),
+        Err(TrySendError::Closed(buf)) => return Err(SendError(buf)),
+        Err(TrySendError::Full(buf)) => buf,
+    };
+    metrics::inc(&metrics::BLOCKED_SENDS);
+    let started = Instant::now();
+    let send = sender.send(buf);
+    tokio::pin!(send);
+    let res = select! {
+        res = &mut send => res,
+        _ = sleep(FULL_WARN_AFTER) => {
+            warn!("{} blocked: channel full for over {:?}, writing to disk is the bottleneck", name, FULL_WARN_AFTER);
+            send.await
+        }
+    };
+    metrics::add(
+        &metrics::BLOCKED_MICROS,
+        started.elapsed().as_micros() as u64,
+    );
+    res
+}
+
+// Logs how often producers were blocked on a full channel
+pub fn log_buffer_stats() {
+    info!(
+        "producers blocked {} times, {:?} in total",
+        metrics::get(&metrics::BLOCKED_SENDS),
+        Duration::from_micros(metrics::get(&metrics::BLOCKED_MICROS))
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(data: &[u8], delimiter: u8) -> Vec<&[u8]> {
+        Lines::new(data, delimiter).collect()
+    }
+
+    #[test]
+    fn lines_keep_mixed_line_endings() {
+        let data = b"unix\nwindows\r\nlast";
+        assert_eq!(
+            lines(data, b'\n'),
+            [&b"unix\n"[..], &b"windows\r\n"[..], &b"last"[..]]
+        );
+        let contents: Vec<&[u8]> = lines(data, b'\n')
+            .into_iter()
+            .map(|line| line_content(line, b'\n'))
+            .collect();
+        assert_eq!(contents, [&b"unix"[..], &b"windows"[..], &b"last"[..]]);
+    }
+
+    #[test]
+    fn lone_carriage_return_is_not_a_line_ending() {
+        let data = b"progress 50%\rprogress 100%\ndone\r";
+        assert_eq!(
+            lines(data, b'\n'),
+            [&b"progress 50%\rprogress 100%\n"[..], &b"done\r"[..]]
+        );
+        assert_eq!(
+            line_content(b"progress 50%\rprogress 100%\n", b'\n'),
+            b"progress 50%\rprogress 100%"
+        );
+        // Without a newline the `\r` is part of the content
+        assert_eq!(line_content(b"done\r", b'\n'), b"done\r");
+    }
+
+    #[test]
+    fn strip_cr_only_turns_crlf_into_lf() {
+        assert_eq!(strip_cr(b"windows\r\n"), &b"windows\n"[..]);
+        assert!(matches!(strip_cr(b"unix\n"), Cow::Borrowed(b"unix\n")));
+        assert_eq!(strip_cr(b"a\rb\n"), &b"a\rb\n"[..]);
+        assert_eq!(strip_cr(b"done\r"), &b"done\r"[..]);
+        assert_eq!(strip_cr(b"\r\n"), &b"\n"[..]);
+    }
+
+    #[test]
+    fn lines_split_on_other_delimiters() {
+        let data = b"first\0second\r\n\0third";
+        assert_eq!(
+            lines(data, b'\0'),
+            [&b"first\0"[..], &b"second\r\n\0"[..], &b"third"[..]]
+        );
+        assert_eq!(line_content(b"first\0", b'\0'), b"first");
+        // `\r\n` only ends lines delimited by `\n`
+        assert_eq!(line_content(b"second\r\n\0", b'\0'), b"second\r\n");
+        assert_eq!(line_content(b"a\r\x1e", b'\x1e'), b"a\r");
+    }
+
+    #[test]
+    fn long_lines_are_split_with_the_delimiter() {
+        let mut tail = b"abcdefgh".to_vec();
+        let lines = split_long_line(&mut tail, 3, b'\0');
+        assert_eq!(lines, [b"abc\0".to_vec(), b"def\0".to_vec()]);
+        assert_eq!(tail, b"gh");
+
+        // A line of exactly `max` bytes is left whole
+        let mut tail = b"abc".to_vec();
+        assert!(split_long_line(&mut tail, 3, b'\0').is_empty());
+        assert_eq!(tail, b"abc");
+
+        // 0 never splits
+        let mut tail = vec![b'x'; 1 << 16];
+        assert!(split_long_line(&mut tail, 0, b'\0').is_empty());
+        assert_eq!(tail.len(), 1 << 16);
+    }
+}
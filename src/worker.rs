@@ -0,0 +1,105 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::thread::JoinHandle;
+
+pub type Job = Box<dyn FnOnce() + Send>;
+
+// Queued jobs per worker above which compression is reported as not keeping up
+const HIGH_WATER_PER_WORKER: usize = 4;
+
+// A fixed pool of threads running the work following rotations (compression and
+// retention), so slow compression never blocks incoming writes and a burst of
+// rotations can't start more compressions than there are workers
+struct Pool {
+    sender: Mutex<Option<mpsc::Sender<Job>>>,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+    queued: Arc<AtomicUsize>, // The jobs queued or running
+    high_water: usize,
+}
+
+static POOL: OnceLock<Pool> = OnceLock::new();
+
+// Starts the pool with the given number of workers, must be called before `submit`
+pub fn init(workers: usize) {
+    let (sender, receiver) = mpsc::channel::<Job>();
+    let receiver = Arc::new(Mutex::new(receiver));
+    let queued = Arc::new(AtomicUsize::new(0));
+    let handles = (0..workers)
+        .map(|_| {
+            let receiver = receiver.clone();
+            let queued = queued.clone();
+            thread::spawn(move || loop {
+                let job = match receiver.lock() {
+                    Ok(receiver) => receiver.recv(),
+                    Err(_) => break,
+                };
+                match job {
+                    Ok(job) => {
+                        job();
+                        queued.fetch_sub(1, Ordering::Relaxed);
+                    }
+                    Err(_) => break,
+                }
+            })
+        })
+        .collect();
+    let pool = Pool {
+        sender: Mutex::new(Some(sender)),
+        handles: Mutex::new(handles),
+        queued,
+        high_water: workers * HIGH_WATER_PER_WORKER,
+    };
+    if POOL.set(pool).is_err() {
+        info!("worker pool already started");
+    }
+}
+
+// Queues a job, it runs right away if the pool was not started
+pub fn submit(name: &str, job: Job) {
+    let pool = match POOL.get() {
+        Some(pool) => pool,
+        None => return job(),
+    };
+    let queued = pool.queued.fetch_add(1, Ordering::Relaxed) + 1;
+    info!("queued {} ({} jobs pending)", name, queued);
+    if queued > pool.high_water {
+        warn!(
+            "{} jobs pending, compression isn't keeping up with rotation",
+            queued
+        );
+    }
+    let sent = match pool.sender.lock() {
+        Ok(sender) => sender.as_ref().map(|s| s.send(job).is_ok()),
+        Err(_) => None,
+    };
+    if sent != Some(true) {
+        pool.queued.fetch_sub(1, Ordering::Relaxed);
+        error!("failed to queue {}", name);
+    }
+}
+
+// Waits for the queued jobs to finish and stops the workers
+pub fn finish() {
+    let pool = match POOL.get() {
+        Some(pool) => pool,
+        None => return,
+    };
+    let queued = pool.queued.load(Ordering::Relaxed);
+    if queued > 0 {
+        info!("waiting for {} jobs to finish", queued);
+    }
+    if let Ok(mut sender) = pool.sender.lock() {
+        drop(sender.take());
+    }
+    let handles = match pool.handles.lock() {
+        Ok(mut handles) => std::mem::take(&mut *handles),
+        Err(_) => return,
+    };
+    for handle in handles {
+        if handle.join().is_err() {
+            error!("worker panicked");
+        }
+    }
+}